@@ -1,17 +1,21 @@
 use std::rc::Rc;
 use std::cell::RefCell;
-use crate::tokens::TreeCode;
+use crate::tokens::{TreeCode, TLoc};
+use crate::ast::{Program, Item, Function, Ident, TypeName, Block, Stmt, Expr};
+use crate::visit::{self, TreeVisitor};
 
 pub struct MTree {
     pub token : TreeCode,
+    pub loc : TLoc,
     pub children : Vec<Rc<RefCell<MTree>>>
 }
 
 impl MTree {
 
-    pub fn new(token : TreeCode) -> Rc<RefCell<MTree>> {
+    pub fn with_loc(token : TreeCode, loc : TLoc) -> Rc<RefCell<MTree>> {
         Rc::new(RefCell::new(MTree {
             token,
+            loc,
             children : vec![]
         }))
     }
@@ -21,19 +25,180 @@ impl MTree {
     }
 
     pub fn node_string(&self) -> String {
-        format!("{:?}", self.token)
+        match &self.token {
+            // print the operator as written, so unary and binary minus read differently
+            TreeCode::BINARY_OP(op) => format!("BINARY_OP({})", op),
+            TreeCode::UNARY_OP(op) => format!("UNARY_OP({})", op),
+            token => format!("{:?}", token),
+        }
+    }
+
+    pub fn print(&self) {
+        Printer.visit_node(self, 0);
     }
-    
-    fn print_recursively(&self, level : usize) {
-        let shift = 2*level;
+}
+
+// one line per node, indented two spaces per level
+struct Printer;
+
+impl TreeVisitor for Printer {
+    fn visit_node(&mut self, node: &MTree, depth: usize) {
+        let shift = 2*depth;
         print!("{:1$}", "", shift);
-        println!("{}", self.node_string());
-        for child_rc in &self.children {
-            child_rc.borrow().print_recursively(level+1);
+        println!("{}", node.node_string());
+        visit::walk_node(self, node, depth);
+    }
+}
+
+// Lowering: the typed AST as a generic tree, in the shape `parse` prints.
+// Annotations become a TYPE child of the IDENTIFIER they describe and every statement
+// of a block is wrapped in a STATEMENT node. Each node keeps the span of the source
+// it came from; an IDENTIFIER spans just the name, even when it carries a TYPE child.
+impl MTree {
+
+    pub fn lower(program: &Program) -> Rc<RefCell<MTree>> {
+        let item_loc = |item: &Item| match item {
+            Item::Function(func) => func.loc.clone(),
+            Item::Error { loc } => loc.clone(),
+        };
+        let loc = match (program.items.first(), program.items.last()) {
+            (Some(first), Some(last)) => item_loc(first).to(&item_loc(last)),
+            _ => TLoc::empty(),
+        };
+        let root = MTree::with_loc(TreeCode::PROGRAM, loc);
+        for item in &program.items {
+            let node = match item {
+                Item::Function(func) => Self::lower_function(func),
+                Item::Error { loc } => MTree::with_loc(TreeCode::ERROR, loc.clone()),
+            };
+            root.borrow_mut()._push(node);
         }
+        root
     }
 
-    pub fn print(&self) {
-        self.print_recursively(0);
+    // func children = [name (with an optional TYPE child), params, block]
+    fn lower_function(func: &Function) -> Rc<RefCell<MTree>> {
+        let func_node = MTree::with_loc(TreeCode::FUNCTION, func.loc.clone());
+        func_node.borrow_mut()._push(Self::lower_ident(&func.name, func.ret.as_ref()));
+
+        let param_list = MTree::with_loc(TreeCode::PARAM_LIST, func.params_loc.clone());
+        for param in &func.params {
+            let loc = match &param.ty {
+                Some(ty) => param.name.loc.to(&ty.loc),
+                None => param.name.loc.clone(),
+            };
+            let param_node = MTree::with_loc(TreeCode::PARAMETER, loc);
+            param_node.borrow_mut()._push(Self::lower_ident(&param.name, param.ty.as_ref()));
+            param_list.borrow_mut()._push(param_node);
+        }
+        func_node.borrow_mut()._push(param_list);
+
+        func_node.borrow_mut()._push(Self::lower_block(&func.body));
+        func_node
+    }
+
+    fn lower_ident(ident: &Ident, ty: Option<&TypeName>) -> Rc<RefCell<MTree>> {
+        let id_node = MTree::with_loc(TreeCode::IDENTIFIER(ident.name.clone()), ident.loc.clone());
+        if let Some(ty) = ty {
+            id_node.borrow_mut()._push(MTree::with_loc(TreeCode::TYPE(ty.name.clone()), ty.loc.clone()));
+        }
+        id_node
+    }
+
+    fn lower_block(block: &Block) -> Rc<RefCell<MTree>> {
+        let block_node = MTree::with_loc(TreeCode::BLOCK, block.loc.clone());
+        for stmt in &block.stmts {
+            let node = match stmt {
+                // a statement that failed to parse never got its STATEMENT node
+                Stmt::Error { loc } => MTree::with_loc(TreeCode::ERROR, loc.clone()),
+                _ => {
+                    let statement_node = MTree::with_loc(TreeCode::STATEMENT, stmt.loc().clone());
+                    if let Some(inner) = Self::lower_stmt(stmt) {
+                        statement_node.borrow_mut()._push(inner);
+                    }
+                    statement_node
+                }
+            };
+            block_node.borrow_mut()._push(node);
+        }
+        block_node
+    }
+
+    fn lower_stmt(stmt: &Stmt) -> Option<Rc<RefCell<MTree>>> {
+        let node = match stmt {
+            Stmt::Let { name, ty, init, loc } => {
+                let node = MTree::with_loc(TreeCode::LET, loc.clone());
+                node.borrow_mut()._push(Self::lower_ident(name, ty.as_ref()));
+                if let Some(init) = init {
+                    node.borrow_mut()._push(Self::lower_expr(init));
+                }
+                node
+            }
+            Stmt::Assign { target, value, loc } => {
+                let node = MTree::with_loc(TreeCode::ASSIGN, loc.clone());
+                node.borrow_mut()._push(Self::lower_ident(target, None));
+                node.borrow_mut()._push(Self::lower_expr(value));
+                node
+            }
+            Stmt::If { cond, then_block, else_block, loc } => {
+                let node = MTree::with_loc(TreeCode::IF, loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(cond));
+                node.borrow_mut()._push(Self::lower_block(then_block));
+                if let Some(else_block) = else_block {
+                    node.borrow_mut()._push(Self::lower_block(else_block));
+                }
+                node
+            }
+            Stmt::While { cond, body, loc } => {
+                let node = MTree::with_loc(TreeCode::WHILE, loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(cond));
+                node.borrow_mut()._push(Self::lower_block(body));
+                node
+            }
+            Stmt::Return { value, loc } => {
+                let node = MTree::with_loc(TreeCode::RETURN, loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(value));
+                node
+            }
+            Stmt::Print { value, loc } => {
+                let node = MTree::with_loc(TreeCode::PRINT, loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(value));
+                node
+            }
+            Stmt::Block(block) => Self::lower_block(block),
+            Stmt::Expr { expr, .. } => Self::lower_expr(expr),
+            Stmt::Empty { .. } => return None,
+            Stmt::Error { loc } => MTree::with_loc(TreeCode::ERROR, loc.clone()),
+        };
+        Some(node)
+    }
+
+    fn lower_expr(expr: &Expr) -> Rc<RefCell<MTree>> {
+        match expr {
+            Expr::Int { value, loc } => MTree::with_loc(TreeCode::INT_LITERAL(*value), loc.clone()),
+            Expr::Bool { value, loc } => MTree::with_loc(TreeCode::BOOL_LITERAL(*value), loc.clone()),
+            Expr::Str { value, loc } => MTree::with_loc(TreeCode::STRING_LITERAL(value.clone()), loc.clone()),
+            Expr::Var(ident) => Self::lower_ident(ident, None),
+            // call children = [name, args...]
+            Expr::Call { name, args, loc } => {
+                let node = MTree::with_loc(TreeCode::FUNCTION_CALL(name.name.clone()), loc.clone());
+                node.borrow_mut()._push(Self::lower_ident(name, None));
+                for arg in args {
+                    node.borrow_mut()._push(Self::lower_expr(arg));
+                }
+                node
+            }
+            Expr::Unary { op, operand, loc } => {
+                let node = MTree::with_loc(TreeCode::UNARY_OP(*op), loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(operand));
+                node
+            }
+            Expr::Binary { op, left, right, loc } => {
+                let node = MTree::with_loc(TreeCode::BINARY_OP(*op), loc.clone());
+                node.borrow_mut()._push(Self::lower_expr(left));
+                node.borrow_mut()._push(Self::lower_expr(right));
+                node
+            }
+        }
     }
 }
@@ -1,171 +1,695 @@
-use std::collections::HashMap;
-use std::rc::Rc;
-use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use crate::mtree::MTree;
-use crate::tokens::TreeCode;
+use crate::ast::{Program, Function, Ident, Block, Stmt, Expr};
+use crate::visit::{self, Visitor};
+use crate::tokens::TLoc;
+use crate::diagnostics::Diagnostic;
 
-//scope stack
+#[derive(Clone, Copy, PartialEq)]
+enum BindingKind {
+    Variable,
+    Parameter,
+}
+
+impl BindingKind {
+    fn describe(self) -> &'static str {
+        match self {
+            BindingKind::Variable => "variable",
+            BindingKind::Parameter => "parameter",
+        }
+    }
+}
+
+// Settings for checks that a program may reasonably want to turn off
+pub struct Options {
+    // warn when a declaration hides a variable or parameter of an enclosing scope
+    pub warn_shadowing: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { warn_shadowing: true }
+    }
+}
+
+// what an earlier declaration of the same name means for a new one
+enum Clash {
+    None,
+    // declared again in the same scope
+    Redeclared(usize),
+    // hides a binding from an enclosing scope
+    Shadows(usize),
+}
+
+// one declared variable or parameter and how it is used
+struct Binding {
+    name: String,
+    kind: BindingKind,
+    loc: TLoc,
+    reads: usize,
+    writes: usize,
+}
+
+//scope stack: variables and parameters only, each name mapped to its binding id.
+//functions live in their own namespace, see Signature
 struct ScopeStack {
-    stack: Vec<HashMap<String, ()>>,
+    stack: Vec<HashMap<String, usize>>,
+    // every binding declared so far, indexed by id
+    bindings: Vec<Binding>,
+    // identifiers (reads and assignment/let targets) -> id of the binding they name;
+    // an identifier is keyed by where it starts, as no two can start at the same place
+    resolved: HashMap<(usize, usize), usize>,
+    warn_shadowing: bool,
 }
 
 impl ScopeStack {
-    fn new() -> Self {
+    fn new(warn_shadowing: bool) -> Self {
         ScopeStack {
             stack: vec![HashMap::new()],
+            bindings: vec![],
+            resolved: HashMap::new(),
+            warn_shadowing,
         }
     }
 
     fn push(&mut self) { self.stack.push(HashMap::new()); }
-    fn pop(&mut self) { self.stack.pop(); }
 
-    fn declare(&mut self, name: &str) {
-        self.stack.last_mut().unwrap().insert(name.to_string(), ());
+    // returns the ids declared in the scope being left, in declaration order
+    fn pop(&mut self) -> Vec<usize> {
+        let mut ids: Vec<usize> = self.stack.pop().map(|s| s.into_values().collect()).unwrap_or_default();
+        ids.sort();
+        ids
+    }
+
+    // declares the name held by an identifier and reports which earlier
+    // declaration of the same name, if any, it collides with
+    fn declare(&mut self, ident: &Ident, kind: BindingKind) -> Clash {
+        let clash = match (self.stack.last().unwrap().get(&ident.name), self.lookup(&ident.name)) {
+            (Some(&same), _) => Clash::Redeclared(same),
+            (None, Some(outer)) => Clash::Shadows(outer),
+            (None, None) => Clash::None,
+        };
+        let id = self.bindings.len();
+        self.bindings.push(Binding {
+            name: ident.name.clone(),
+            kind,
+            loc: ident.loc.clone(),
+            reads: 0,
+            writes: 0,
+        });
+        self.resolved.insert(ident_key(ident), id);
+        self.stack.last_mut().unwrap().insert(ident.name.clone(), id);
+        clash
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.stack.iter().rev().find_map(|scope| scope.get(name).copied())
     }
 
     fn is_declared(&self, name: &str) -> bool {
-        for scope in self.stack.iter().rev() {
-            if scope.contains_key(name) {
-                return true;
-            }
-        }
-        false
+        self.lookup(name).is_some()
     }
+
+    // links an identifier to the binding its name currently refers to
+    fn resolve(&mut self, ident: &Ident) -> Option<usize> {
+        let id = self.lookup(&ident.name)?;
+        self.resolved.insert(ident_key(ident), id);
+        Some(id)
+    }
+
+    fn id_of(&self, ident: &Ident) -> Option<usize> {
+        self.resolved.get(&ident_key(ident)).copied()
+    }
+}
+
+fn ident_key(ident: &Ident) -> (usize, usize) {
+    (ident.loc.first.row, ident.loc.first.col)
+}
+
+// what a call site needs to know about the function it calls
+struct Signature {
+    params: usize,
+    loc: TLoc,
 }
 
 // analysis
-pub fn analyze(root: Rc<RefCell<MTree>>) {
-    let mut scopes = ScopeStack::new();
-    analyze_node(root, &mut scopes, None);
+// Returns errors as well as warnings (unused names, values never read, dead functions)
+pub fn analyze(program: &Program, options: &Options) -> Vec<Diagnostic> {
+    let mut scopes = ScopeStack::new(options.warn_shadowing);
+    let mut diags = Vec::new();
+    // function names are collected up front, so calls may come before the definition
+    let signatures = collect_signatures(program, &mut diags);
+    for func in program.functions() {
+        analyze_function(func, &mut scopes, &signatures, &mut diags);
+    }
+    report_unreachable_functions(program, &signatures, &mut diags);
+    diags
 }
 
-fn is_function_name(node: &Rc<RefCell<MTree>>, parent: &Rc<RefCell<MTree>>) -> bool {
-    if let TreeCode::FUNCTION = parent.borrow().token {
-        if let Some(first) = parent.borrow().children.get(0) {
-            return Rc::ptr_eq(first, node);
+// the function namespace; the first definition of a name wins, later ones are reported
+fn collect_signatures(program: &Program, diags: &mut Vec<Diagnostic>) -> HashMap<String, Signature> {
+    let mut signatures: HashMap<String, Signature> = HashMap::new();
+    for func in program.functions() {
+        if let Some(first) = signatures.get(&func.name.name) {
+            diags.push(
+                Diagnostic::error(
+                    format!("function `{}` is defined more than once", func.name.name),
+                    func.name.loc.clone(),
+                )
+                .with_note_at("first defined here", first.loc.clone()),
+            );
+            continue;
+        }
+        // the program is started by calling `main` with no arguments
+        if func.name.name == "main" && !func.params.is_empty() {
+            diags.push(Diagnostic::error("`main` must take no parameters", func.params_loc.clone()));
         }
+        let sig = Signature { params: func.params.len(), loc: func.name.loc.clone() };
+        signatures.insert(func.name.name.clone(), sig);
     }
-    false
+    signatures
+}
+
+fn plural(n: usize, word: &str) -> String {
+    if n == 1 { format!("{} {}", n, word) } else { format!("{} {}s", n, word) }
 }
 
-fn analyze_node(
-    node: Rc<RefCell<MTree>>,
+fn analyze_function(
+    func: &Function,
     scopes: &mut ScopeStack,
-    parent: Option<Rc<RefCell<MTree>>>
+    signatures: &HashMap<String, Signature>,
+    diags: &mut Vec<Diagnostic>
 ) {
-    let n = node.borrow();
+    scopes.push();
 
-    match &n.token {
+    //params
+    for param in &func.params {
+        declare(scopes, &param.name, BindingKind::Parameter, diags);
+    }
 
-        // program predeclare func names
-        TreeCode::PROGRAM => {
+    //block
+    analyze_block(&func.body, scopes, signatures, diags);
 
-            for c in &n.children {
-                let func = c.borrow();
-                if let TreeCode::FUNCTION = func.token {
-                    if let Some(name_node) = func.children.get(0) {
-                        if let TreeCode::IDENTIFIER(name) = &name_node.borrow().token {
-                            scopes.declare(name); // <-- FIX
-                        }
-                    }
-                }
-            }
+    let params = scopes.pop();
+    report_unused(scopes, &params, diags);
 
-            for c in &n.children {
-                analyze_node(c.clone(), scopes, Some(node.clone()));
+    // reads are all counted now, so values that are never read can be found
+    let mut liveness = Liveness { scopes, report: true, diags };
+    liveness.block(&func.body, HashSet::new());
+}
+
+fn analyze_block(
+    block: &Block,
+    scopes: &mut ScopeStack,
+    signatures: &HashMap<String, Signature>,
+    diags: &mut Vec<Diagnostic>
+) {
+    scopes.push();
+    for stmt in &block.stmts {
+        analyze_stmt(stmt, scopes, signatures, diags);
+    }
+    let locals = scopes.pop();
+    report_unused(scopes, &locals, diags);
+}
+
+fn analyze_stmt(
+    stmt: &Stmt,
+    scopes: &mut ScopeStack,
+    signatures: &HashMap<String, Signature>,
+    diags: &mut Vec<Diagnostic>
+) {
+    match stmt {
+        // the initializer cannot see the new name
+        Stmt::Let { name, init, .. } => {
+            if let Some(init) = init {
+                analyze_expr(init, scopes, signatures, diags);
+            }
+            declare(scopes, name, BindingKind::Variable, diags);
+            if init.is_some() && let Some(b) = scopes.id_of(name) {
+                scopes.bindings[b].writes += 1;
             }
         }
 
-        // func children = [name, params, block]
-        TreeCode::FUNCTION => {
-            scopes.push();
-
-            //params
-            if let Some(param_list) = n.children.get(1) {
-                let pl = param_list.borrow();
-                for p in &pl.children {
-                    if let TreeCode::PARAMETER = &p.borrow().token {
-                        if let Some(id) = p.borrow().children.get(0) {
-                            if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                                scopes.declare(name);
-                            }
-                        }
-                    }
-                }
+        //assign
+        Stmt::Assign { target, value, .. } => {
+            match scopes.resolve(target) {
+                Some(b) => scopes.bindings[b].writes += 1,
+                None => diags.push(Diagnostic::error(
+                    format!("assigning to undeclared variable `{}`", target.name),
+                    target.loc.clone(),
+                )),
             }
+            analyze_expr(value, scopes, signatures, diags);
+        }
 
-            //block
-            if let Some(block) = n.children.get(2) {
-                analyze_node(block.clone(), scopes, Some(node.clone()));
+        Stmt::If { cond, then_block, else_block, .. } => {
+            analyze_expr(cond, scopes, signatures, diags);
+            analyze_block(then_block, scopes, signatures, diags);
+            if let Some(else_block) = else_block {
+                analyze_block(else_block, scopes, signatures, diags);
             }
-
-            scopes.pop();
         }
 
-        TreeCode::BLOCK => {
-            scopes.push();
-            for c in &n.children {
-                analyze_node(c.clone(), scopes, Some(node.clone()));
-            }
-            scopes.pop();
+        Stmt::While { cond, body, .. } => {
+            analyze_expr(cond, scopes, signatures, diags);
+            analyze_block(body, scopes, signatures, diags);
         }
 
-        TreeCode::LET => {
-            if let Some(id) = n.children.get(0) {
-                if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                    scopes.declare(name);
+        Stmt::Return { value, .. } |
+        Stmt::Print { value, .. } |
+        Stmt::Expr { expr: value, .. } => analyze_expr(value, scopes, signatures, diags),
+
+        Stmt::Block(block) => analyze_block(block, scopes, signatures, diags),
+
+        Stmt::Empty { .. } | Stmt::Error { .. } => {}
+    }
+}
+
+fn analyze_expr(
+    expr: &Expr,
+    scopes: &mut ScopeStack,
+    signatures: &HashMap<String, Signature>,
+    diags: &mut Vec<Diagnostic>
+) {
+    match expr {
+        // identifier usage
+        Expr::Var(ident) => {
+            if let Some(b) = scopes.resolve(ident) {
+                scopes.bindings[b].reads += 1;
+            } else {
+                let name = &ident.name;
+                let mut diag = Diagnostic::error(
+                    format!("variable `{}` used before declaration", name),
+                    ident.loc.clone(),
+                );
+                if signatures.contains_key(name) {
+                    diag = diag.with_note(format!("`{}` is a function; call it with `{}(...)`", name, name));
                 }
+                diags.push(diag);
             }
         }
 
-        // identifier usage except when func name
-        TreeCode::IDENTIFIER(name) => {
-            if let Some(parent_node) = parent {
-                if is_function_name(&node, &parent_node) {
-                    // Function names are not variable uses
-                } else if !scopes.is_declared(name) {
-                    eprintln!("SEMANTIC ERROR: variable `{}` used before declaration", name);
+        Expr::Call { name: callee, args, .. } => {
+            let name = &callee.name;
+            match signatures.get(name) {
+                Some(sig) if args.len() != sig.params => {
+                    diags.push(
+                        Diagnostic::error(
+                            format!(
+                                "function `{}` takes {} but {} supplied",
+                                name,
+                                plural(sig.params, "argument"),
+                                if args.len() == 1 { "1 was".to_string() } else { format!("{} were", args.len()) },
+                            ),
+                            callee.loc.clone(),
+                        )
+                        .with_note_at(format!("`{}` is defined here", name), sig.loc.clone()),
+                    );
+                }
+                Some(_) => {}
+                None if scopes.is_declared(name) => {
+                    diags.push(Diagnostic::error(
+                        format!("`{}` is a variable, not a function", name),
+                        callee.loc.clone(),
+                    ));
+                }
+                None => {
+                    diags.push(Diagnostic::error(
+                        format!("cannot find function `{}`", name),
+                        callee.loc.clone(),
+                    ));
                 }
             }
+            for arg in args {
+                analyze_expr(arg, scopes, signatures, diags);
+            }
         }
 
-        //assign
-        TreeCode::ASSIGN => {
-            if let Some(id) = n.children.get(0) {
-                if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                    if !scopes.is_declared(name) {
-                        eprintln!(
-                            "SEMANTIC ERROR: assigning to undeclared variable `{}`",
-                            name
-                        );
+        Expr::Unary { operand, .. } => analyze_expr(operand, scopes, signatures, diags),
+        Expr::Binary { left, right, .. } => {
+            analyze_expr(left, scopes, signatures, diags);
+            analyze_expr(right, scopes, signatures, diags);
+        }
+
+        Expr::Int { .. } | Expr::Bool { .. } | Expr::Str { .. } => {}
+    }
+}
+
+// Declares a binding, reporting a name declared twice in one scope (an error) and,
+// unless turned off, a name that hides one from an enclosing scope (a warning)
+fn declare(
+    scopes: &mut ScopeStack,
+    ident: &Ident,
+    kind: BindingKind,
+    diags: &mut Vec<Diagnostic>
+) {
+    let name = &ident.name;
+    let loc = ident.loc.clone();
+    match scopes.declare(ident, kind) {
+        Clash::None => {}
+        Clash::Redeclared(first) => {
+            let message = match kind {
+                BindingKind::Parameter => format!("parameter `{}` is declared more than once", name),
+                _ => format!("`{}` is already declared in this scope", name),
+            };
+            diags.push(
+                Diagnostic::error(message, loc)
+                    .with_note_at("first declared here", scopes.bindings[first].loc.clone()),
+            );
+        }
+        Clash::Shadows(outer) if scopes.warn_shadowing => {
+            let outer = &scopes.bindings[outer];
+            diags.push(
+                Diagnostic::warning(
+                    format!("{} `{}` shadows an outer {}", kind.describe(), name, outer.kind.describe()),
+                    loc,
+                )
+                .with_note_at(format!("the shadowed {} is declared here", outer.kind.describe()), outer.loc.clone())
+                .with_note("run with `--allow-shadowing` to silence this warning"),
+            );
+        }
+        Clash::Shadows(_) => {}
+    }
+}
+
+// Names starting with "_" are deliberately unused and never reported
+fn report_unused(scopes: &ScopeStack, ids: &[usize], diags: &mut Vec<Diagnostic>) {
+    for &id in ids {
+        let b = &scopes.bindings[id];
+        if b.reads > 0 || b.name.starts_with('_') {
+            continue;
+        }
+        let message = match (b.kind, b.writes) {
+            (BindingKind::Parameter, _) => format!("unused parameter `{}`", b.name),
+            (BindingKind::Variable, 0) => format!("unused variable `{}`", b.name),
+            (BindingKind::Variable, _) => format!("variable `{}` is assigned but never read", b.name),
+        };
+        diags.push(
+            Diagnostic::warning(message, b.loc.clone())
+                .with_note(format!("prefix it with an underscore (`_{}`) to silence this warning", b.name)),
+        );
+    }
+}
+
+// Backward liveness over a function body. A binding is live at a point if some path from
+// there reads it before it is assigned again; an assignment whose target is not live right
+// after it stores a value nobody can read. Works on binding ids, so shadowed names stay apart.
+struct Liveness<'a> {
+    scopes: &'a ScopeStack,
+    report: bool,
+    diags: &'a mut Vec<Diagnostic>,
+}
+
+impl Liveness<'_> {
+    // takes the set live after the block, returns the set live before it
+    fn block(&mut self, block: &Block, live: HashSet<usize>) -> HashSet<usize> {
+        let mut live = live;
+        for stmt in block.stmts.iter().rev() {
+            live = self.stmt(stmt, live);
+        }
+        live
+    }
+
+    // takes the set live after the statement, returns the set live before it
+    fn stmt(&mut self, stmt: &Stmt, live: HashSet<usize>) -> HashSet<usize> {
+        let mut live = live;
+        match stmt {
+            Stmt::Let { name: target, init: None, .. } => {
+                if let Some(id) = self.scopes.id_of(target) {
+                    live.remove(&id);
+                }
+            }
+            Stmt::Let { name: target, init: Some(value), .. } |
+            Stmt::Assign { target, value, .. } => {
+                if let Some(id) = self.scopes.id_of(target) && !live.remove(&id) {
+                    self.dead_store(id, target);
+                }
+                self.uses(value, &mut live);
+            }
+            Stmt::Return { value, .. } => {
+                live.clear();
+                self.uses(value, &mut live);
+            }
+            Stmt::If { cond, then_block, else_block, .. } => {
+                let mut out = self.block(then_block, live.clone());
+                match else_block {
+                    Some(else_block) => out.extend(self.block(else_block, live)),
+                    None => out.extend(live),
+                }
+                self.uses(cond, &mut out);
+                live = out;
+            }
+            // iterate to a fixpoint at the loop head, then make one more pass over the
+            // body to report from the final sets
+            Stmt::While { cond, body, .. } => {
+                let report = self.report;
+                self.report = false;
+                let mut head = live.clone();
+                self.uses(cond, &mut head);
+                loop {
+                    let mut next = self.block(body, head.clone());
+                    next.extend(live.iter().copied());
+                    self.uses(cond, &mut next);
+                    if next == head {
+                        break;
                     }
+                    head = next;
                 }
+                self.report = report;
+                self.block(body, head.clone());
+                live = head;
             }
+            Stmt::Block(block) => live = self.block(block, live),
+            // print and expression statements only read
+            Stmt::Print { value, .. } | Stmt::Expr { expr: value, .. } => self.uses(value, &mut live),
+            Stmt::Empty { .. } | Stmt::Error { .. } => {}
+        }
+        live
+    }
 
-            if let Some(expr) = n.children.get(1) {
-                analyze_node(expr.clone(), scopes, Some(node.clone()));
-            }
+    fn uses(&self, expr: &Expr, live: &mut HashSet<usize>) {
+        Reads { scopes: self.scopes, live }.visit_expr(expr);
+    }
+
+    // bindings that are never read at all already got an "unused" warning
+    fn dead_store(&mut self, id: usize, target: &Ident) {
+        let b = &self.scopes.bindings[id];
+        if !self.report || b.reads == 0 || b.name.starts_with('_') {
+            return;
         }
+        self.diags.push(
+            Diagnostic::warning(format!("value assigned to `{}` is never read", b.name), target.loc.clone())
+                .with_note("it is overwritten or goes out of scope before being used"),
+        );
+    }
+}
 
-        // recursively analyze children
-        TreeCode::PRINT |
-        TreeCode::IF |
-        TreeCode::WHILE |
-        TreeCode::RETURN |
-        TreeCode::OPERATOR(_) |
-        TreeCode::FUNCTION_CALL(_) |
-        TreeCode::INT_LITERAL(_) |
-        TreeCode::BOOL_LITERAL(_) |
-        TreeCode::EOF |
-        TreeCode::PARAM_LIST |
-        TreeCode::PARAMETER |
-        TreeCode::STATEMENT |
-        TreeCode::EXPRESSION =>
-            {
-                for c in &n.children {
-                    analyze_node(c.clone(), scopes, Some(node.clone()));
-                }
+// adds every binding an expression reads to the live set
+struct Reads<'a> {
+    scopes: &'a ScopeStack,
+    live: &'a mut HashSet<usize>,
+}
+
+impl Visitor<'_> for Reads<'_> {
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let Expr::Var(ident) = expr && let Some(id) = self.scopes.id_of(ident) {
+            self.live.insert(id);
+        }
+        visit::walk_expr(self, expr);
+    }
+}
+
+// names of the functions called anywhere in a function body
+#[derive(Default)]
+struct Callees<'ast> {
+    names: Vec<&'ast str>,
+}
+
+impl<'ast> Visitor<'ast> for Callees<'ast> {
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        if let Expr::Call { name, .. } = expr {
+            self.names.push(&name.name);
+        }
+        visit::walk_expr(self, expr);
+    }
+}
+
+// Warns about functions that no chain of calls starting at `main` can reach
+fn report_unreachable_functions(
+    program: &Program,
+    signatures: &HashMap<String, Signature>,
+    diags: &mut Vec<Diagnostic>
+) {
+    if !signatures.contains_key("main") {
+        return;
+    }
+
+    let mut calls: HashMap<&str, Vec<&str>> = HashMap::new();
+    for func in program.functions() {
+        let mut callees = Callees::default();
+        callees.visit_block(&func.body);
+        calls.entry(func.name.name.as_str()).or_default().extend(callees.names);
+    }
+
+    let mut reached = HashSet::from(["main"]);
+    let mut queue = VecDeque::from(["main"]);
+    while let Some(name) = queue.pop_front() {
+        for &callee in calls.get(name).into_iter().flatten() {
+            if reached.insert(callee) {
+                queue.push_back(callee);
             }
+        }
+    }
+
+    let mut names: Vec<(&String, &Signature)> = signatures.iter().collect();
+    names.sort_by_key(|(_, sig)| (sig.loc.first.row, sig.loc.first.col));
+    for (name, sig) in names {
+        if !reached.contains(name.as_str()) && !name.starts_with('_') {
+            diags.push(
+                Diagnostic::warning(format!("function `{}` is never used", name), sig.loc.clone())
+                    .with_note("it cannot be reached by any chain of calls starting at `main`"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    // every dead-store warning as (variable, row, column), in source order
+    fn dead_stores(src: &str) -> Vec<(String, usize, usize)> {
+        let program = parse_valid(src);
+        let mut stores: Vec<_> = analyze(&program, &Options::default()).into_iter()
+            .filter_map(|d| {
+                let name = d.message.strip_prefix("value assigned to `")?.strip_suffix("` is never read")?;
+                Some((name.to_string(), d.loc.first.row, d.loc.first.col))
+            })
+            .collect();
+        stores.sort_by_key(|&(_, row, col)| (row, col));
+        stores
+    }
+
+    #[test]
+    fn store_overwritten_later_in_a_loop_body_is_dead() {
+        let src = "func main() [
+    let i = 0;
+    let t = 0;
+    while i < 3 [
+        t = i;
+        t = 5;
+        print t;
+        i = i + 1;
+    ]
+]";
+        assert_eq!(dead_stores(src), [("t".to_string(), 3, 9), ("t".to_string(), 5, 9)]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn store_read_on_the_next_iteration_is_live() {
+        // `x = i` is only read by the `print` at the top of the following iteration,
+        // which the loop fixpoint has to carry back around
+        let src = "func main() [
+    let i = 0;
+    let x = 0;
+    while i < 3 [
+        print x;
+        x = i;
+        i = i + 1;
+    ]
+]";
+        assert_eq!(dead_stores(src), []);
+    }
+
+    #[test]
+    fn let_overwritten_in_both_branches_is_dead() {
+        let src = "func f(c: bool) [
+    let x = 1;
+    if c [ x = 2; ] else [ x = 3; ]
+    print x;
+]
+func main() [ f(true); ]";
+        assert_eq!(dead_stores(src), [("x".to_string(), 2, 9)]);
+    }
+
+    #[test]
+    fn let_overwritten_in_one_branch_is_live() {
+        let src = "func f(c: bool) [
+    let x = 1;
+    if c [ x = 2; ]
+    print x;
+]
+func main() [ f(true); ]";
+        assert_eq!(dead_stores(src), []);
+    }
+
+    #[test]
+    fn store_before_a_return_that_does_not_read_it_is_dead() {
+        let src = "func f(n: int) -> int [
+    let r = n;
+    if n > 0 [ r = 2; return 1; ]
+    return r;
+]
+func main() [ print f(1); ]";
+        assert_eq!(dead_stores(src), [("r".to_string(), 3, 16)]);
+    }
+
+    #[test]
+    fn main_with_parameters_is_rejected() {
+        let program = parse_valid("func main(argc) [ print argc; ]");
+        let errors: Vec<_> = analyze(&program, &Options::default()).into_iter()
+            .filter(|d| d.message == "`main` must take no parameters")
+            .map(|d| (d.loc.first.row, d.loc.first.col))
+            .collect();
+        assert_eq!(errors, [(1, 10)]);
+    }
+
+    // (row, column)
+    type Pos = (usize, usize);
+
+    // every diagnostic as its message and position, plus where its first note points
+    fn report(src: &str, options: &Options) -> Vec<(String, Pos, Option<Pos>)> {
+        let pos = |loc: &TLoc| (loc.first.row, loc.first.col);
+        analyze(&parse_valid(src), options).into_iter()
+            .map(|d| {
+                let note = d.notes.first().and_then(|n| n.loc.as_ref()).map(pos);
+                (d.message, pos(&d.loc), note)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn redeclaration_in_the_same_scope_is_an_error() {
+        let src = "func main() [
+    let x = 1;
+    let x = 2;
+    print x;
+]";
+        assert_eq!(report(src, &Options::default()), [
+            ("`x` is already declared in this scope".to_string(), (3, 9), Some((2, 9))),
+        ]);
+        assert_eq!(report("func f(a, a) [ print a; ] func main() [ f(1, 2); ]", &Options::default()), [
+            ("parameter `a` is declared more than once".to_string(), (1, 11), Some((1, 8))),
+        ]);
+    }
+
+    #[test]
+    fn shadowing_warns_unless_allowed() {
+        let src = "func f(n) [
+    let x = n;
+    if x > 0 [
+        let n = 2;
+        let x = n;
+        print x;
+    ]
+    print x;
+]
+func main() [ f(1); ]";
+        assert_eq!(report(src, &Options::default()), [
+            ("variable `n` shadows an outer parameter".to_string(), (4, 13), Some((1, 8))),
+            ("variable `x` shadows an outer variable".to_string(), (5, 13), Some((2, 9))),
+        ]);
+        assert_eq!(report(src, &Options { warn_shadowing: false }), []);
+    }
+}
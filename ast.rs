@@ -0,0 +1,121 @@
+use crate::tokens::{TLoc, BinaryOp, UnaryOp};
+
+// Typed syntax tree produced by the parser. Every node's `loc` spans the source it was
+// parsed from, from the start of its first token to the end of its last one; a
+// parenthesized expression spans only what is inside the parentheses.
+// MTree::lower turns it into the generic tree that `parse` prints.
+
+pub struct Program {
+    pub items: Vec<Item>,
+}
+
+pub enum Item {
+    // boxed: a function is far larger than the error placeholder
+    Function(Box<Function>),
+    // a top-level construct the parser could not read; see Parser::synchronize
+    Error { loc: TLoc },
+}
+
+impl Program {
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Function(func) => Some(&**func),
+            Item::Error { .. } => None,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Ident {
+    pub name: String,
+    pub loc: TLoc,
+}
+
+// a type annotation as written; the type checker decides what the name means
+#[derive(Clone)]
+pub struct TypeName {
+    pub name: String,
+    pub loc: TLoc,
+}
+
+#[derive(Clone)]
+pub struct Function {
+    pub name: Ident,
+    pub params: Vec<Param>,
+    // the parenthesized parameter list
+    pub params_loc: TLoc,
+    pub ret: Option<TypeName>,
+    pub body: Block,
+    pub loc: TLoc,
+}
+
+#[derive(Clone)]
+pub struct Param {
+    pub name: Ident,
+    pub ty: Option<TypeName>,
+}
+
+#[derive(Clone)]
+pub struct Block {
+    pub stmts: Vec<Stmt>,
+    pub loc: TLoc,
+}
+
+#[derive(Clone)]
+pub enum Stmt {
+    Let { name: Ident, ty: Option<TypeName>, init: Option<Expr>, loc: TLoc },
+    Assign { target: Ident, value: Expr, loc: TLoc },
+    If { cond: Expr, then_block: Block, else_block: Option<Block>, loc: TLoc },
+    While { cond: Expr, body: Block, loc: TLoc },
+    Return { value: Expr, loc: TLoc },
+    Print { value: Expr, loc: TLoc },
+    Block(Block),
+    // loc also covers the ";", which the expression's own span does not
+    Expr { expr: Expr, loc: TLoc },
+    // a lone ";"
+    Empty { loc: TLoc },
+    // placeholder for a statement the parser could not read; see Parser::synchronize
+    Error { loc: TLoc },
+}
+
+impl Stmt {
+    pub fn loc(&self) -> &TLoc {
+        match self {
+            Stmt::Let { loc, .. } |
+            Stmt::Assign { loc, .. } |
+            Stmt::If { loc, .. } |
+            Stmt::While { loc, .. } |
+            Stmt::Return { loc, .. } |
+            Stmt::Print { loc, .. } |
+            Stmt::Expr { loc, .. } |
+            Stmt::Empty { loc } |
+            Stmt::Error { loc } => loc,
+            Stmt::Block(block) => &block.loc,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Expr {
+    Int { value: i64, loc: TLoc },
+    Bool { value: bool, loc: TLoc },
+    Str { value: String, loc: TLoc },
+    Var(Ident),
+    Call { name: Ident, args: Vec<Expr>, loc: TLoc },
+    Unary { op: UnaryOp, operand: Box<Expr>, loc: TLoc },
+    Binary { op: BinaryOp, left: Box<Expr>, right: Box<Expr>, loc: TLoc },
+}
+
+impl Expr {
+    pub fn loc(&self) -> &TLoc {
+        match self {
+            Expr::Int { loc, .. } |
+            Expr::Bool { loc, .. } |
+            Expr::Str { loc, .. } |
+            Expr::Call { loc, .. } |
+            Expr::Unary { loc, .. } |
+            Expr::Binary { loc, .. } => loc,
+            Expr::Var(ident) => &ident.loc,
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use crate::ast::{Program, Function, Block, Stmt, Expr};
+use crate::dot::dot_escape;
+
+// Control-flow graphs, one per function, for drawing with Graphviz.
+// Straight-line statements are grouped into basic blocks; `if` and `while` end a block
+// with a branch on their condition and `return` jumps to the function's exit.
+// Statements after a return are not drawn (flow::check reports them as unreachable).
+
+pub struct Cfg {
+    pub name: String,
+    // blocks[ENTRY] and blocks[EXIT] are the function's entry and exit points
+    pub blocks: Vec<BasicBlock>,
+}
+
+pub const ENTRY: usize = 0;
+pub const EXIT: usize = 1;
+
+#[derive(Default)]
+pub struct BasicBlock {
+    // statements as they would be written in source, the branch condition last
+    pub lines: Vec<String>,
+    // successor and the edge label ("true"/"false" out of a branch)
+    pub succs: Vec<(usize, Option<&'static str>)>,
+}
+
+pub fn build(func: &Function) -> Cfg {
+    let mut builder = Builder { blocks: vec![BasicBlock::default(), BasicBlock::default()] };
+    let start = builder.new_block();
+    builder.edge(ENTRY, start, None);
+    if let Some(end) = builder.block(&func.body, start) {
+        builder.edge(end, EXIT, None);
+    }
+    Cfg { name: func.name.name.clone(), blocks: builder.blocks }
+}
+
+struct Builder {
+    blocks: Vec<BasicBlock>,
+}
+
+impl Builder {
+    fn new_block(&mut self) -> usize {
+        self.blocks.push(BasicBlock::default());
+        self.blocks.len() - 1
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: Option<&'static str>) {
+        self.blocks[from].succs.push((to, label));
+    }
+
+    // Adds the statements of `block` starting in basic block `current`; returns the
+    // basic block control ends up in, or None if every path has returned
+    fn block(&mut self, block: &Block, mut current: usize) -> Option<usize> {
+        for stmt in &block.stmts {
+            current = self.stmt(stmt, current)?;
+        }
+        Some(current)
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, current: usize) -> Option<usize> {
+        match stmt {
+            Stmt::Return { value, .. } => {
+                self.blocks[current].lines.push(format!("return {}", expr_text(value)));
+                self.edge(current, EXIT, None);
+                None
+            }
+            Stmt::If { cond, then_block, else_block, .. } => {
+                self.blocks[current].lines.push(format!("if {}", expr_text(cond)));
+                let then_start = self.new_block();
+                self.edge(current, then_start, Some("true"));
+                let then_end = self.block(then_block, then_start);
+                let else_end = match else_block {
+                    Some(else_block) => {
+                        let else_start = self.new_block();
+                        self.edge(current, else_start, Some("false"));
+                        self.block(else_block, else_start)
+                    }
+                    None => Some(current),
+                };
+                // a join block is only needed where two paths meet
+                match (then_end, else_end) {
+                    (None, None) => return None,
+                    (Some(end), None) | (None, Some(end)) if end != current => return Some(end),
+                    _ => {}
+                }
+                let join = self.new_block();
+                if let Some(end) = then_end {
+                    self.edge(end, join, None);
+                }
+                if let Some(end) = else_end {
+                    self.edge(end, join, if end == current { Some("false") } else { None });
+                }
+                Some(join)
+            }
+            Stmt::While { cond, body, .. } => {
+                let head = self.new_block();
+                self.edge(current, head, None);
+                self.blocks[head].lines.push(format!("while {}", expr_text(cond)));
+                let body_start = self.new_block();
+                self.edge(head, body_start, Some("true"));
+                if let Some(end) = self.block(body, body_start) {
+                    self.edge(end, head, None);
+                }
+                // as in flow::falls_through, `while true` is only left through a return
+                if let Expr::Bool { value: true, .. } = cond {
+                    return None;
+                }
+                let after = self.new_block();
+                self.edge(head, after, Some("false"));
+                Some(after)
+            }
+            Stmt::Block(block) => self.block(block, current),
+            Stmt::Let { name, ty, init, .. } => {
+                let mut line = format!("let {}", name.name);
+                if let Some(ty) = ty {
+                    line.push_str(&format!(": {}", ty.name));
+                }
+                if let Some(init) = init {
+                    line.push_str(&format!(" = {}", expr_text(init)));
+                }
+                self.blocks[current].lines.push(line);
+                Some(current)
+            }
+            Stmt::Assign { target, value, .. } => {
+                self.blocks[current].lines.push(format!("{} = {}", target.name, expr_text(value)));
+                Some(current)
+            }
+            Stmt::Print { value, .. } => {
+                self.blocks[current].lines.push(format!("print {}", expr_text(value)));
+                Some(current)
+            }
+            Stmt::Expr { expr, .. } => {
+                self.blocks[current].lines.push(expr_text(expr));
+                Some(current)
+            }
+            Stmt::Empty { .. } | Stmt::Error { .. } => Some(current),
+        }
+    }
+}
+
+// the expression as source text; nested operators are parenthesized
+fn expr_text(expr: &Expr) -> String {
+    let operand = |e: &Expr| match e {
+        Expr::Unary { .. } | Expr::Binary { .. } => format!("({})", expr_text(e)),
+        _ => expr_text(e),
+    };
+    match expr {
+        Expr::Int { value, .. } => value.to_string(),
+        Expr::Bool { value, .. } => value.to_string(),
+        Expr::Str { value, .. } => format!("{:?}", value),
+        Expr::Var(ident) => ident.name.clone(),
+        Expr::Call { name, args, .. } => {
+            let args: Vec<String> = args.iter().map(expr_text).collect();
+            format!("{}({})", name.name, args.join(", "))
+        }
+        Expr::Unary { op, operand: inner, .. } => format!("{}{}", op, operand(inner)),
+        Expr::Binary { op, left, right, .. } => format!("{} {} {}", operand(left), op, operand(right)),
+    }
+}
+
+// every function's graph as a cluster of one Graphviz digraph
+pub fn to_dot(program: &Program) -> String {
+    let mut out = String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+    for (f, func) in program.functions().enumerate() {
+        let cfg = build(func);
+        out.push_str(&format!("    subgraph cluster_{} {{\n", f));
+        out.push_str(&format!("        label=\"{}\";\n", dot_escape(&cfg.name)));
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            let label = match i {
+                ENTRY => "entry".to_string(),
+                EXIT => "exit".to_string(),
+                // `\l` ends a left-justified line
+                _ => block.lines.iter().map(|line| format!("{}\\l", dot_escape(line))).collect(),
+            };
+            let shape = if i == ENTRY || i == EXIT { ", shape=ellipse" } else { "" };
+            out.push_str(&format!("        f{}_b{} [label=\"{}\"{}];\n", f, i, label, shape));
+        }
+        for (i, block) in cfg.blocks.iter().enumerate() {
+            for (succ, label) in &block.succs {
+                let attrs = label.map(|l| format!(" [label=\"{}\"]", l)).unwrap_or_default();
+                out.push_str(&format!("        f{}_b{} -> f{}_b{}{};\n", f, i, f, succ, attrs));
+            }
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    fn cfg_of(src: &str) -> Cfg {
+        let program = parse_valid(src);
+        build(program.functions().next().expect("a function"))
+    }
+
+    fn lines(cfg: &Cfg) -> Vec<Vec<&str>> {
+        cfg.blocks.iter().map(|b| b.lines.iter().map(String::as_str).collect()).collect()
+    }
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, Option<&'static str>)> {
+        cfg.blocks.iter().enumerate()
+            .flat_map(|(i, b)| b.succs.iter().map(move |&(succ, label)| (i, succ, label)))
+            .collect()
+    }
+
+    #[test]
+    fn if_without_else_joins_after_the_then_block() {
+        let cfg = cfg_of("func f(x) [ if x < 0 [ print 1; ] print 2; ]");
+        assert_eq!(lines(&cfg), [vec![], vec![], vec!["if x < 0"], vec!["print 1"], vec!["print 2"]]);
+        assert_eq!(edges(&cfg), [
+            (ENTRY, 2, None),
+            (2, 3, Some("true")),
+            (2, 4, Some("false")),
+            (3, 4, None),
+            (4, EXIT, None),
+        ]);
+    }
+
+    #[test]
+    fn if_whose_branches_both_return_has_no_join() {
+        let cfg = cfg_of("func f(x) -> int [ if x < 0 [ return 0; ] else [ return -x; ] ]");
+        assert_eq!(lines(&cfg), [vec![], vec![], vec!["if x < 0"], vec!["return 0"], vec!["return -x"]]);
+        assert_eq!(edges(&cfg), [
+            (ENTRY, 2, None),
+            (2, 3, Some("true")),
+            (2, 4, Some("false")),
+            (3, EXIT, None),
+            (4, EXIT, None),
+        ]);
+    }
+
+    #[test]
+    fn while_true_is_only_left_through_a_return() {
+        let cfg = cfg_of("func f() -> int [ let i = 0; while true [ i = i + 1; if i > 3 [ return i; ] ] ]");
+        assert_eq!(lines(&cfg), [
+            vec![], vec![],
+            vec!["let i = 0"],
+            vec!["while true"],
+            vec!["i = i + 1", "if i > 3"],
+            vec!["return i"],
+            vec![],
+        ]);
+        assert_eq!(edges(&cfg), [
+            (ENTRY, 2, None),
+            (2, 3, None),
+            (3, 4, Some("true")),
+            (4, 5, Some("true")),
+            (4, 6, Some("false")),
+            (5, EXIT, None),
+            (6, 3, None),
+        ]);
+    }
+
+    #[test]
+    fn while_loop_exits_on_false() {
+        let cfg = cfg_of("func f(n) [ while n > 0 [ n = n - 1; ] print n; ]");
+        assert_eq!(lines(&cfg)[2..], [vec![], vec!["while n > 0"], vec!["n = n - 1"], vec!["print n"]]);
+        assert_eq!(edges(&cfg), [
+            (ENTRY, 2, None),
+            (2, 3, None),
+            (3, 4, Some("true")),
+            (3, 5, Some("false")),
+            (4, 3, None),
+            (5, EXIT, None),
+        ]);
+    }
+}
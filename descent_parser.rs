@@ -1,106 +1,192 @@
-use crate::parser::Parser;
-use crate::tokens::{TCode, TreeCode};
-use crate::mtree::MTree;
-use std::cell::RefCell;
-use std::rc::Rc;
+use crate::parser::{Parser, ParseResult};
+use crate::tokens::{TCode, TLoc, BinaryOp, UnaryOp};
+use crate::ast::{Program, Item, Function, Param, TypeName, Ident, Block, Stmt, Expr};
+use crate::diagnostics::Diagnostic;
 
 impl Parser {
 
     // Program Structure
     // program = { function } ;
-    pub fn parse(&mut self) -> Rc<RefCell<MTree>> {
-        let root = MTree::new(TreeCode::PROGRAM);
+    //
+    // Lexical and syntax errors do not stop the parse: each one is recorded, replaced by an Error node
+    // and the parser resynchronizes, so the (possibly partial) tree comes back with every
+    // error found in the input.
+    pub fn parse(&mut self) -> (Program, Vec<Diagnostic>) {
+        let mut items = vec![];
         loop {
             match self.curr() {
                 TCode::KW_FUNC => {
-                    self.parse_func(&root);
+                    match self.parse_func() {
+                        Ok(func) => items.push(Item::Function(Box::new(func))),
+                        Err(diag) => {
+                            items.push(Item::Error { loc: self.record_error(diag) });
+                            self.skip_to_function();
+                        }
+                    }
                 }
                 TCode::EOI => break,
-                other => panic!(
-                    "Expected function declaration or end of input, found {:?}",
-                    other
-                ),
+                _ => {
+                    let diag = self.unexpected("function declaration or end of input");
+                    items.push(Item::Error { loc: self.record_error(diag) });
+                    self.skip_to_function();
+                }
+            }
+        }
+        (Program { items }, self.take_diagnostics())
+    }
+
+    // After an error at the top level: only a function can start there, so everything up to
+    // the next `func` belongs to the broken construct
+    fn skip_to_function(&mut self) {
+        while !matches!(self.curr(), TCode::KW_FUNC | TCode::EOI) {
+            self.advance();
+        }
+    }
+
+    // Store a syntax error; returns the location for the Error node left where the broken construct would have been
+    fn record_error(&mut self, diag: Diagnostic) -> TLoc {
+        let loc = diag.loc.clone();
+        self.diagnostics.push(diag);
+        loc
+    }
+
+    // Panic-mode recovery: skip tokens up to the next synchronization point.
+    // A ";" is consumed since it ends the broken statement, "]" and "func" are left for the
+    // enclosing block or program to handle. Bracketed groups met along the way are skipped
+    // whole, and closing one counts as the end of the statement.
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        loop {
+            match self.curr() {
+                TCode::EOI | TCode::KW_FUNC => return,
+                TCode::SEMICOLON if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                TCode::BRACKET_R if depth == 0 => return,
+                TCode::BRACKET_R => {
+                    depth -= 1;
+                    self.advance();
+                    if depth == 0 {
+                        return;
+                    }
+                }
+                TCode::BRACKET_L => {
+                    depth += 1;
+                    self.advance();
+                }
+                _ => self.advance(),
             }
         }
-        root
+    }
+
+    fn parse_ident(&mut self) -> ParseResult<Ident> {
+        let (name, loc) = self.expect_id()?;
+        Ok(Ident { name, loc })
     }
 
     // Function Definitions
-    // function = "func" ID "(" [ parameters ] ")" block ;
-    pub fn parse_func(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    // function = "func" ID "(" [ parameters ] ")" [ "->" type ] block ;
+    pub fn parse_func(&mut self) -> ParseResult<Function> {
         //self.indent_print("parse_func()");
         self.indent_increment();
 
-        self.expect(TCode::KW_FUNC);
+        let start = self.curr_loc();
+        self.expect(TCode::KW_FUNC)?;
 
-        let func_node = MTree::new(TreeCode::FUNCTION);
-        ast_node.borrow_mut()._push(func_node.clone());
+        let name = self.parse_ident()?;
 
-        let name = self.expect_id();
-        let id_node = MTree::new(TreeCode::IDENTIFIER(name));
-        func_node.borrow_mut()._push(id_node);
+        let params_start = self.curr_loc();
+        self.expect(TCode::PAREN_L)?;
+        let params = self.parse_parameter_list()?;
+        self.expect(TCode::PAREN_R)?;
+        let params_loc = self.span_from(&params_start);
 
-        self.expect(TCode::PAREN_L);
-        self.parse_parameter_list(&func_node);
-        self.expect(TCode::PAREN_R);
+        let ret = if self.accept(TCode::ARROW) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
 
-        self.parse_block_nest(&func_node);
+        let body = self.parse_block_nest()?;
 
         self.indent_decrement();
+        Ok(Function { name, params, params_loc, ret, body, loc: self.span_from(&start) })
     }
 
-    // parameters = ID { "," ID } ;
-    pub fn parse_parameter_list(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    // parameters = parameter { "," parameter } ;
+    pub fn parse_parameter_list(&mut self) -> ParseResult<Vec<Param>> {
         //self.indent_print("parse_parameter_list()");
         self.indent_increment();
 
-        let param_list_node = MTree::new(TreeCode::PARAM_LIST);
-        ast_node.borrow_mut()._push(param_list_node.clone());
-
+        let mut params = vec![];
         if self.curr() == &TCode::PAREN_R {
-            return;
+            self.indent_decrement();
+            return Ok(params);
         }
-        self.parse_parameter(&param_list_node);
+        params.push(self.parse_parameter()?);
         while self.accept(TCode::COMMA) {
-            self.parse_parameter(&param_list_node);
+            params.push(self.parse_parameter()?);
         }
         self.indent_decrement();
+        Ok(params)
     }
 
-    pub fn parse_parameter(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    // parameter = ID [ ":" type ] ;
+    pub fn parse_parameter(&mut self) -> ParseResult<Param> {
         //self.indent_print("parse_parameter()");
         self.indent_increment();
 
-        let param_node = MTree::new(TreeCode::PARAMETER);
-
         // get the actual name!!
-        let name = self.expect_id();
-        let id_node = MTree::new(TreeCode::IDENTIFIER(name));
-
-        // attach identifier under parameter node
-        param_node.borrow_mut()._push(id_node);
-
-        // attach parameter to list
-        ast_node.borrow_mut()._push(param_node);
+        let name = self.parse_ident()?;
+        let ty = if self.accept(TCode::COLON) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
 
         self.indent_decrement();
+        Ok(Param { name, ty })
+    }
+
+    // Type annotations
+    // type = ID ;
+    // the name is checked by the type checker
+    pub fn parse_type(&mut self) -> ParseResult<TypeName> {
+        let (name, loc) = self.expect_id()?;
+        Ok(TypeName { name, loc })
     }
 
 
     // Blocks
     // block = "[" { statement } "]" ;
-    pub fn parse_block_nest(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    pub fn parse_block_nest(&mut self) -> ParseResult<Block> {
         //self.indent_print("parse_block_nest()");
         self.indent_increment();
 
-        let block_node = MTree::new(TreeCode::BLOCK);
-        ast_node.borrow_mut()._push(block_node.clone());
+        let loc = self.curr_loc();
+        let mut stmts = vec![];
 
-        self.expect(TCode::BRACKET_L);
+        self.expect(TCode::BRACKET_L)?;
         while !self.accept(TCode::BRACKET_R) {
-            self.parse_statement(&block_node);
+            // the block was never closed; let the program level pick up from here
+            if matches!(self.curr(), TCode::KW_FUNC | TCode::EOI) {
+                self.record_error(
+                    Diagnostic::error("missing `]` to close this block", self.prev_loc())
+                        .with_note_at("the block is opened here", loc.clone()),
+                );
+                break;
+            }
+            match self.parse_statement() {
+                Ok(stmt) => stmts.push(stmt),
+                Err(diag) => {
+                    stmts.push(Stmt::Error { loc: self.record_error(diag) });
+                    self.synchronize();
+                }
+            }
         }
         self.indent_decrement();
+        Ok(Block { stmts, loc: self.span_from(&loc) })
     }
 
     // Statements
@@ -113,298 +199,339 @@ impl Parser {
     // | print_stmt
     // | expr_stmt
     // ;
-    pub fn parse_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    pub fn parse_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_statement()");
         self.indent_increment();
 
-        let statement_node = MTree::new(TreeCode::STATEMENT);
-        ast_node.borrow_mut()._push(statement_node.clone());
+        let loc = self.curr_loc();
 
-        match self.curr() {
-            TCode::KW_RETURN => self.parse_return_statement(&statement_node),
-            TCode::KW_LET => self.parse_let_statement(&statement_node),
-            TCode::KW_IF => self.parse_if_statement(&statement_node),
-            TCode::KW_PRINT => self.parse_print_statement(&statement_node),
-            TCode::KW_WHILE => self.parse_while_statement(&statement_node),
-            TCode::BRACKET_L => self.parse_block_nest(&statement_node),
+        let stmt = match self.curr() {
+            TCode::KW_RETURN => self.parse_return_statement()?,
+            TCode::KW_LET => self.parse_let_statement()?,
+            TCode::KW_IF => self.parse_if_statement()?,
+            TCode::KW_PRINT => self.parse_print_statement()?,
+            TCode::KW_WHILE => self.parse_while_statement()?,
+            TCode::BRACKET_L => Stmt::Block(self.parse_block_nest()?),
             TCode::ID(_s) => {
                 if self.peek_next().code == TCode::OP_ASSIGN {
-                        self.parse_assign_statement(&statement_node);
+                        self.parse_assign_statement()?
                 } else {
-                    let expr_node = self.parse_expression();
-                    statement_node.borrow_mut()._push(expr_node);
-                    self.expect(TCode::SEMICOLON);
+                    let expr = self.parse_expression()?;
+                    self.expect(TCode::SEMICOLON)?;
+                    Stmt::Expr { expr, loc: self.span_from(&loc) }
                 }
             },
-            TCode::SEMICOLON => self.advance(),
-            other => panic!("Unexpected statement token, found {:?}", other),
-        }
+            TCode::SEMICOLON => {
+                self.advance();
+                Stmt::Empty { loc }
+            }
+            _ => return Err(self.unexpected("statement")),
+        };
         self.indent_decrement();
+        Ok(stmt)
     }
 
     // return_stmt = "return" expression ";" ;
-    pub fn parse_return_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    pub fn parse_return_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_return_statement");
         self.indent_increment();
 
-        let return_node = MTree::new(TreeCode::RETURN);
+        let loc = self.curr_loc();
 
-        self.expect(TCode::KW_RETURN);
-        let expr_node = self.parse_expression();
-        return_node.borrow_mut()._push(expr_node);
+        self.expect(TCode::KW_RETURN)?;
+        let value = self.parse_expression()?;
 
-        self.expect(TCode::SEMICOLON);
-        ast_node.borrow_mut()._push(return_node.clone());
+        self.expect(TCode::SEMICOLON)?;
 
         self.indent_decrement();
+        Ok(Stmt::Return { value, loc: self.span_from(&loc) })
     }
 
-    // let_stmt = "let" ID ";" ;
-    pub fn parse_let_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    // let_stmt = "let" ID [ ":" type ] [ "=" expression ] ";" ;
+    pub fn parse_let_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_let_statement()");
         self.indent_increment();
 
-        let let_node = MTree::new(TreeCode::LET);
-        self.expect(TCode::KW_LET);
-        let name = self.expect_id();
-        let id_node = MTree::new(TreeCode::IDENTIFIER(name));
-        let_node.borrow_mut()._push(id_node);
+        let loc = self.curr_loc();
+        self.expect(TCode::KW_LET)?;
+        let name = self.parse_ident()?;
+        let ty = if self.accept(TCode::COLON) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        let mut init = None;
         if self.curr() == &TCode::OP_ASSIGN {
-            self.expect(TCode::OP_ASSIGN);
-            let expr_node = self.parse_expression();
-            let_node.borrow_mut()._push(expr_node);
+            self.expect(TCode::OP_ASSIGN)?;
+            init = Some(self.parse_expression()?);
         }
 
-        self.expect(TCode::SEMICOLON);
-        ast_node.borrow_mut()._push(let_node);
+        self.expect(TCode::SEMICOLON)?;
         self.indent_decrement();
+        Ok(Stmt::Let { name, ty, init, loc: self.span_from(&loc) })
     }
 
-    // if_stmt = "if" expression block "else" block ;
-    pub fn parse_if_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    // if_stmt = "if" expression block [ "else" block ] ;
+    pub fn parse_if_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_if_statement()");
         self.indent_increment();
 
-        let if_node = MTree::new(TreeCode::IF);
+        let loc = self.curr_loc();
 
-        self.expect(TCode::KW_IF);
-        let expr_node= self.parse_expression();
-        if_node.borrow_mut()._push(expr_node.clone());
+        self.expect(TCode::KW_IF)?;
+        let cond = self.parse_expression()?;
 
-        self.parse_block_nest(&if_node);
-        if self.accept(TCode::KW_ELSE) {
-            self.parse_block_nest(&if_node);
-        }
+        let then_block = self.parse_block_nest()?;
+        let else_block = if self.accept(TCode::KW_ELSE) {
+            Some(self.parse_block_nest()?)
+        } else {
+            None
+        };
 
-        ast_node.borrow_mut()._push(if_node.clone());
         self.indent_decrement();
+        Ok(Stmt::If { cond, then_block, else_block, loc: self.span_from(&loc) })
     }
 
     // print_stmt = "print" expression ";" ;
-    pub fn parse_print_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    pub fn parse_print_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_print_statement()");
         self.indent_increment();
 
-        let print_node = MTree::new(TreeCode::PRINT);
+        let loc = self.curr_loc();
 
-        self.expect(TCode::KW_PRINT);
-        let expr_node = self.parse_expression();
-        print_node.borrow_mut()._push(expr_node.clone());
+        self.expect(TCode::KW_PRINT)?;
+        let value = self.parse_expression()?;
 
-        self.expect(TCode::SEMICOLON);
-        ast_node.borrow_mut()._push(print_node.clone());
+        self.expect(TCode::SEMICOLON)?;
         self.indent_decrement();
+        Ok(Stmt::Print { value, loc: self.span_from(&loc) })
     }
 
 
     // while_stmt = "while" expression block ;
-    pub fn parse_while_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
-        let while_node = MTree::new(TreeCode::WHILE);
-        self.expect(TCode::KW_WHILE);
-        let expr_node = self.parse_expression();
-        while_node.borrow_mut()._push(expr_node);
-
+    pub fn parse_while_statement(&mut self) -> ParseResult<Stmt> {
+        let loc = self.curr_loc();
+        self.expect(TCode::KW_WHILE)?;
+        let cond = self.parse_expression()?;
 
-        self.parse_block_nest(&while_node);
+        let body = self.parse_block_nest()?;
 
-        ast_node.borrow_mut()._push(while_node);
+        Ok(Stmt::While { cond, body, loc: self.span_from(&loc) })
     }
 
     // assign_stmt = ID "=" expression ";" ;
-    pub fn parse_assign_statement(&mut self, ast_node: &Rc<RefCell<MTree>>) {
+    pub fn parse_assign_statement(&mut self) -> ParseResult<Stmt> {
         //self.indent_print("parse_assign_statement()");
         self.indent_increment();
 
-        let assign_node = MTree::new(TreeCode::ASSIGN);
-        ast_node.borrow_mut()._push(assign_node.clone());
+        let loc = self.curr_loc();
 
-        if let TCode::ID(name) = self.curr() {
-            let id_node = MTree::new(TreeCode::IDENTIFIER(name.clone()));
-            assign_node.borrow_mut()._push(id_node);
-            self.advance();
-        } else {
-            panic!("Expected identifier at assignment start");
-        }
+        let target = self.parse_ident()?;
 
-        self.expect(TCode::OP_ASSIGN);
+        self.expect(TCode::OP_ASSIGN)?;
 
-        let expr_node = self.parse_expression();
-        assign_node.borrow_mut()._push(expr_node);
+        let value = self.parse_expression()?;
 
-        self.expect(TCode::SEMICOLON);
+        self.expect(TCode::SEMICOLON)?;
 
         self.indent_decrement();
+        Ok(Stmt::Assign { target, value, loc: self.span_from(&loc) })
     }
 
 
     // expr_stmt = expression ";" ;
-    pub fn parse_expression(&mut self) -> Rc<RefCell<MTree>> {
+    pub fn parse_expression(&mut self) -> ParseResult<Expr> {
         self.parse_logic_or()
     }
 
     // logic_or = logic_and { "|" logic_and } ;
-    fn parse_logic_or(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_logic_and();
-        while self.curr() == &TCode::OP_OR {
-            let op = self.curr().clone();
+    fn parse_logic_or(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_logic_and()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::OR]) {
             self.advance();
-            let right = self.parse_logic_and();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_logic_and()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // logic_and = equality { "&" equality } ;
-    fn parse_logic_and(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_equality();
-        while self.curr() == &TCode::OP_AND {
-            let op = self.curr().clone();
+    fn parse_logic_and(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_equality()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::AND]) {
             self.advance();
-            let right = self.parse_equality();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_equality()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // equality = relational { ( "==" | "!=" ) relational } ;
-    fn parse_equality(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_relational();
-        while self.curr() == &TCode::OP_EQUAL || self.curr() == &TCode::OP_NOT_EQUAL {
-            let op = self.curr().clone();
+    fn parse_equality(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_relational()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::EQUAL, BinaryOp::NOT_EQUAL]) {
             self.advance();
-            let right = self.parse_relational();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_relational()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // relational = additive { ( "<" | ">" ) additive } ;
-    fn parse_relational(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_additive();
-        while self.curr() == &TCode::OP_LT || self.curr() == &TCode::OP_GT {
-            let op = self.curr().clone();
+    fn parse_relational(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_additive()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::LT, BinaryOp::GT]) {
             self.advance();
-            let right = self.parse_additive();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_additive()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // additive = multiplicative { ( "+" | "-" ) multiplicative } ;
-    fn parse_additive(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_multiplicative();
-        while self.curr() == &TCode::OP_ADD || self.curr() == &TCode::OP_SUB {
-            let op = self.curr().clone();
+    fn parse_additive(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_multiplicative()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::ADD, BinaryOp::SUB]) {
             self.advance();
-            let right = self.parse_multiplicative();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_multiplicative()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // multiplicative = unary { ( "*" | "/" ) unary } ;
-    fn parse_multiplicative(&mut self) -> Rc<RefCell<MTree>> {
-        let mut left = self.parse_unary();
-        while self.curr() == &TCode::OP_MUL || self.curr() == &TCode::OP_DIV {
-            let op = self.curr().clone();
+    fn parse_multiplicative(&mut self) -> ParseResult<Expr> {
+        let start = self.curr_loc();
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.binary_op(&[BinaryOp::MUL, BinaryOp::DIV]) {
             self.advance();
-            let right = self.parse_unary();
-            left = self.make_binary_op_node(op, left, right);
+            let right = self.parse_unary()?;
+            left = self.make_binary_op_node(op, self.span_from(&start), left, right);
         }
-        left
+        Ok(left)
     }
 
     // unary = ( "!" | "-" ) unary | primary ;
-    fn parse_unary(&mut self) -> Rc<RefCell<MTree>> {
-        match self.curr() {
-            TCode::OP_NOT | TCode::OP_SUB => {
-                let op = self.curr().clone();
+    fn parse_unary(&mut self) -> ParseResult<Expr> {
+        match UnaryOp::from_token(self.curr()) {
+            Some(op) => {
+                let loc = self.curr_loc();
                 self.advance();
-                let expr = self.parse_unary();
-                let node = MTree::new(TreeCode::OPERATOR(format!("{:?}", op)));
-                node.borrow_mut()._push(expr);
-                node
+                let operand = self.parse_unary()?;
+                Ok(Expr::Unary { op, operand: Box::new(operand), loc: self.span_from(&loc) })
             }
-            _ => self.parse_primary(),
+            None => self.parse_primary(),
         }
     }
 
     // primary =
     // INT
     // | BOOL
+    // | STRING
     // | ID
     // | function_call
     // | "(" expression ")"
-    fn parse_primary(&mut self) -> Rc<RefCell<MTree>> {
+    fn parse_primary(&mut self) -> ParseResult<Expr> {
         let current_token = self.curr().clone();
+        let loc = self.curr_loc();
 
         match current_token {
-            TCode::INT(val) => {
-                let node = MTree::new(TreeCode::INT_LITERAL(val.clone()));
+            TCode::INT(value) => {
                 self.advance();
-                node
+                Ok(Expr::Int { value, loc })
             }
-            TCode::BOOL(val) => {
-                let node = MTree::new(TreeCode::BOOL_LITERAL(val.clone()));
+            TCode::BOOL(value) => {
                 self.advance();
-                node
+                Ok(Expr::Bool { value, loc })
+            }
+            TCode::STRING(value) => {
+                self.advance();
+                Ok(Expr::Str { value, loc })
             }
             TCode::ID(name) => {
-                let id_node = MTree::new(TreeCode::IDENTIFIER(name.clone()));
+                let name = Ident { name, loc: loc.clone() };
                 self.advance();
                 if self.curr() == &TCode::PAREN_L {
                     self.advance();
-                    let func_call_node = MTree::new(TreeCode::FUNCTION_CALL(name.clone()));
-                    func_call_node.borrow_mut()._push(id_node.clone());
+                    let mut args = vec![];
                     if self.curr() != &TCode::PAREN_R {
                         loop {
-                            let arg = self.parse_expression();
-                            func_call_node.borrow_mut()._push(arg);
+                            args.push(self.parse_expression()?);
                             if !self.accept(TCode::COMMA) {
                                 break;
                             }
                         }
                     }
-                    self.expect(TCode::PAREN_R);
-                    func_call_node
+                    self.expect(TCode::PAREN_R)?;
+                    Ok(Expr::Call { name, args, loc: self.span_from(&loc) })
                 } else {
-                    id_node
+                    Ok(Expr::Var(name))
                 }
             }
             TCode::PAREN_L => {
                 self.advance();
-                let expr = self.parse_expression();
-                self.expect(TCode::PAREN_R);
-                expr
+                let expr = self.parse_expression()?;
+                self.expect(TCode::PAREN_R)?;
+                Ok(expr)
             }
-            other => panic!("Unexpected token in expression: {:?}", other),
+            _ => Err(self.unexpected("expression")),
         }
     }
 
-    fn make_binary_op_node(&self, op: TCode, left: Rc<RefCell<MTree>>, right: Rc<RefCell<MTree>>) -> Rc<RefCell<MTree>> {
-        let op_node = MTree::new(TreeCode::OPERATOR(format!("{:?}", op)));
-        op_node.borrow_mut()._push(left);
-        op_node.borrow_mut()._push(right);
-        op_node
+    // the operator at the current token, if it is one of `ops` (one precedence level)
+    fn binary_op(&self, ops: &[BinaryOp]) -> Option<BinaryOp> {
+        BinaryOp::from_token(self.curr()).filter(|op| ops.contains(op))
+    }
+
+    fn make_binary_op_node(&self, op: BinaryOp, loc: TLoc, left: Expr, right: Expr) -> Expr {
+        Expr::Binary { op, left: Box::new(left), right: Box::new(right), loc }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn unclosed_block_points_at_its_opening_bracket() {
+        let (program, errors) = Parser::new(Lexer::new("func main() [\n    print 1;\n")).parse();
+        assert_eq!(program.functions().count(), 1);
+        assert_eq!(errors.len(), 1);
+        let err = &errors[0];
+        assert_eq!(err.message, "missing `]` to close this block");
+        assert_eq!((err.loc.first.row, err.loc.first.col), (2, 12));
+        let note = err.notes[0].loc.as_ref().expect("a located note");
+        assert_eq!((note.first.row, note.first.col), (1, 13));
+    }
+
+    #[test]
+    fn unclosed_block_before_the_next_function_is_reported() {
+        let (program, errors) = Parser::new(Lexer::new("func a() [ print 1;\nfunc b() [ print 2; ]")).parse();
+        assert_eq!(program.functions().count(), 2);
+        let messages: Vec<&str> = errors.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(messages, ["missing `]` to close this block"]);
+    }
+
+    #[test]
+    fn stray_tokens_after_a_function_are_reported() {
+        let (program, errors) = Parser::new(Lexer::new("func main() [ print 1; ]\nprint 2;\n]")).parse();
+        assert_eq!(program.functions().count(), 1);
+        assert_eq!(errors.len(), 1);
+        let err = &errors[0];
+        assert_eq!(err.message, "expected function declaration or end of input, but found `PRINT`");
+        assert_eq!((err.loc.first.row, err.loc.first.col), (2, 1));
+    }
+
+    #[test]
+    fn stray_bracket_after_a_function_is_reported() {
+        let (_, errors) = Parser::new(Lexer::new("func main() [ print 1; ] ]\nfunc f() [ ]")).parse();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].loc.first.row, errors[0].loc.first.col), (1, 26));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use crate::tokens::TLoc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// A secondary message attached to a diagnostic, optionally pointing somewhere else in the source
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub message: String,
+    pub loc: Option<TLoc>,
+}
+
+// A single report produced by any stage (lexer, parser, analyzer, evaluator)
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub loc: TLoc,
+    pub notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>, loc: TLoc) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            loc,
+            notes: vec![],
+        }
+    }
+
+    pub fn error(message: impl Into<String>, loc: TLoc) -> Self {
+        Self::new(Severity::Error, message, loc)
+    }
+
+    pub fn warning(message: impl Into<String>, loc: TLoc) -> Self {
+        Self::new(Severity::Warning, message, loc)
+    }
+
+    pub fn with_note(mut self, message: impl Into<String>) -> Self {
+        self.notes.push(Note { message: message.into(), loc: None });
+        self
+    }
+
+    pub fn with_note_at(mut self, message: impl Into<String>, loc: TLoc) -> Self {
+        self.notes.push(Note { message: message.into(), loc: Some(loc) });
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    // Render rustc-style: header, file position, the offending source line and a caret underline
+    //
+    // error: expected `;`, but found `]`
+    //  --> Test1:3:14
+    //   |
+    // 3 |     x = inc(4)
+    //   |              ^
+    pub fn render(&self, path: &str, src: &str) -> String {
+        let lines: Vec<&str> = src.lines().collect();
+        let gutter = self.max_row().to_string().len();
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+
+        if !self.loc.is_empty() {
+            out.push_str(&render_snippet(&self.loc, path, &lines, gutter, '^'));
+        }
+        for note in &self.notes {
+            out.push_str(&format!("{:>w$}= note: {}\n", "", note.message, w = gutter + 1));
+            if let Some(loc) = note.loc.as_ref().filter(|l| !l.is_empty()) {
+                out.push_str(&render_snippet(loc, path, &lines, gutter, '-'));
+            }
+        }
+        out
+    }
+
+    fn max_row(&self) -> usize {
+        self.notes.iter()
+            .filter_map(|n| n.loc.as_ref())
+            .map(|l| l.first.row)
+            .fold(self.loc.first.row, usize::max)
+    }
+}
+
+// Number of columns the underline should cover, clamped to the first line of the span
+fn span_width(loc: &TLoc, line_len: usize) -> usize {
+    let first = &loc.first;
+    let width = if loc.last.row == first.row && loc.last.col + loc.last.len > first.col {
+        usize::max(first.len, loc.last.col + loc.last.len - first.col)
+    } else if loc.last.row > first.row {
+        (line_len + 1).saturating_sub(first.col)
+    } else {
+        first.len
+    };
+    usize::max(width, 1)
+}
+
+fn render_snippet(loc: &TLoc, path: &str, lines: &[&str], gutter: usize, mark: char) -> String {
+    let row = loc.first.row;
+    let col = loc.first.col;
+    let mut out = format!("{:>w$}--> {}:{}:{}\n", "", path, row, col, w = gutter);
+
+    let line = match lines.get(row - 1) {
+        Some(line) => *line,
+        None => return out,
+    };
+    let chars: Vec<char> = line.chars().collect();
+
+    // keep tabs in the padding so the carets line up with the source line
+    let pad: String = chars.iter()
+        .take(col.saturating_sub(1))
+        .map(|c| if *c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let marks: String = std::iter::repeat_n(mark, span_width(loc, chars.len())).collect();
+
+    out.push_str(&format!("{:>w$} |\n", "", w = gutter));
+    out.push_str(&format!("{:>w$} | {}\n", row, line, w = gutter));
+    out.push_str(&format!("{:>w$} | {}{}\n", "", pad, marks, w = gutter));
+    out
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.severity, self.message)?;
+        if !self.loc.is_empty() {
+            write!(f, " (line {}, column {})", self.loc.first.row, self.loc.first.col)?;
+        }
+        for note in &self.notes {
+            write!(f, "\n  = note: {}", note.message)?;
+            if let Some(loc) = &note.loc && !loc.is_empty() {
+                write!(f, " (line {}, column {})", loc.first.row, loc.first.col)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.is_error())
+}
@@ -0,0 +1,84 @@
+use crate::mtree::MTree;
+use crate::visit::{self, TreeVisitor};
+
+// Graphviz output. The parse tree is drawn as one box per node, labelled as
+// `MTree::print` shows it; control-flow graphs are drawn by cfg::to_dot.
+
+impl MTree {
+    pub fn to_dot(&self) -> String {
+        let mut writer = DotWriter {
+            out: String::from("digraph mtree {\n    node [shape=box, fontname=\"monospace\"];\n"),
+            next_id: 0,
+            parents: vec![],
+        };
+        writer.visit_node(self, 0);
+        writer.out.push_str("}\n");
+        writer.out
+    }
+}
+
+// nodes are numbered in preorder, each followed by the edge from its parent
+struct DotWriter {
+    out: String,
+    next_id: usize,
+    parents: Vec<usize>,
+}
+
+impl TreeVisitor for DotWriter {
+    fn visit_node(&mut self, node: &MTree, depth: usize) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.out.push_str(&format!("    n{} [label=\"{}\"];\n", id, dot_escape(&node.node_string())));
+        if let Some(parent) = self.parents.last() {
+            self.out.push_str(&format!("    n{} -> n{};\n", parent, id));
+        }
+        self.parents.push(id);
+        visit::walk_node(self, node, depth);
+        self.parents.pop();
+    }
+}
+
+// text made safe to put between the quotes of a DOT label
+pub fn dot_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::parse_valid;
+    use crate::mtree::MTree;
+
+    #[test]
+    fn tree_is_drawn_in_preorder_with_escaped_labels() {
+        let program = parse_valid("func main() [ print \"a\\\"b\"; ]");
+        let expected = r#"digraph mtree {
+    node [shape=box, fontname="monospace"];
+    n0 [label="PROGRAM"];
+    n1 [label="FUNCTION"];
+    n0 -> n1;
+    n2 [label="IDENTIFIER(\"main\")"];
+    n1 -> n2;
+    n3 [label="PARAM_LIST"];
+    n1 -> n3;
+    n4 [label="BLOCK"];
+    n1 -> n4;
+    n5 [label="STATEMENT"];
+    n4 -> n5;
+    n6 [label="PRINT"];
+    n5 -> n6;
+    n7 [label="STRING_LITERAL(\"a\\\"b\")"];
+    n6 -> n7;
+}
+"#;
+        assert_eq!(MTree::lower(&program).borrow().to_dot(), expected);
+    }
+}
@@ -1,17 +1,49 @@
 use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
 
-use crate::mtree::MTree;
-use crate::tokens::TreeCode;
+use crate::ast::{Program, Function, Block, Stmt, Expr};
+use crate::tokens::{TLoc, BinaryOp, UnaryOp};
+use crate::diagnostics::Diagnostic;
+
+pub type RuntimeResult<T> = Result<T, Diagnostic>;
 
 #[derive(Clone)]
 pub enum Value {
     INT(i64),
-    FUNC(Rc<RefCell<MTree>>),
+    BOOL(bool),
+    STR(String),
+    FUNC(Rc<Function>),
     VOID,
 }
 
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::INT(i) => write!(f, "{}", i),
+            Value::BOOL(b) => write!(f, "{}", b),
+            Value::STR(s) => write!(f, "{}", s),
+            Value::FUNC(_) => write!(f, "unknown"),
+            Value::VOID => write!(f, "void"),
+        }
+    }
+}
+
+impl Value {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::INT(_) => "int",
+            Value::BOOL(_) => "bool",
+            Value::STR(_) => "string",
+            Value::FUNC(_) => "function",
+            Value::VOID => "void",
+        }
+    }
+}
+
+// A name is in `declared` from its `let` on, but only has an entry in `variables`
+// once a value has been assigned to it
 pub struct Frame {
     variables: HashMap<String, Value>,
     declared: HashSet<String>,
@@ -27,35 +59,54 @@ impl Frame {
         }
     }
 
-    pub fn declare(&mut self, name: &str, val: Value) {
+    pub fn declare(&mut self, name: &str, val: Option<Value>) {
         self.declared.insert(name.to_string());
-        self.variables.insert(name.to_string(), val);
+        match val {
+            Some(val) => self.variables.insert(name.to_string(), val),
+            None => self.variables.remove(name),
+        };
     }
-    pub fn set(&mut self, name: &str, val: Value) {
-        if self.variables.contains_key(name) {
+    pub fn set(&mut self, name: &str, val: Value, loc: &TLoc) -> RuntimeResult<()> {
+        if self.declared.contains(name) {
             self.variables.insert(name.to_string(), val);
+            Ok(())
         } else if let Some(ref parent) = self.parent {
-            parent.borrow_mut().set(name, val);
+            parent.borrow_mut().set(name, val, loc)
         } else {
-            // Clean error message and stop
-            eprintln!("Runtime Error: variable `{}` used before declaration", name);
-            std::process::exit(1);
+            Err(Diagnostic::error(
+                format!("variable `{}` used before declaration", name),
+                loc.clone(),
+            ))
         }
     }
 
-    pub fn get(&self, name: &str) -> Option<Value> {
-        if self.variables.contains_key(name) {
-            Some(self.variables[name].clone())
+    pub fn get(&self, name: &str, loc: &TLoc) -> RuntimeResult<Value> {
+        if self.declared.contains(name) {
+            match self.variables.get(name) {
+                Some(val) => Ok(val.clone()),
+                None => Err(Diagnostic::error(
+                    format!("variable `{}` used before it was given a value", name),
+                    loc.clone(),
+                )),
+            }
         } else if let Some(ref p) = self.parent {
-            p.borrow().get(name)
+            p.borrow().get(name, loc)
         } else {
-            None
+            Err(Diagnostic::error(
+                format!("variable `{}` used before declaration", name),
+                loc.clone(),
+            ))
         }
     }
 }
 
 pub struct Runtime {
-    pub functions: HashMap<String, Rc<RefCell<MTree>>>,
+    pub functions: HashMap<String, Rc<Function>>,
+}
+
+// Builds a runtime error located at the given expression
+fn runtime_error(expr: &Expr, message: String) -> Diagnostic {
+    Diagnostic::error(message, expr.loc().clone())
 }
 
 impl Runtime {
@@ -63,212 +114,274 @@ impl Runtime {
         Runtime { functions: HashMap::new() }
     }
 
-    pub fn run_program(&mut self, program: Rc<RefCell<MTree>>) {
+    pub fn run_program(&mut self, program: &Program) -> RuntimeResult<()> {
 
-        self.collect_functions(program.clone());
+        self.collect_functions(program);
 
         let main_func = match self.functions.get("main") {
             Some(func) => func.clone(),
             None => {
-                eprintln!("Runtime Error: no `main` function found in the program.");
-                std::process::exit(1);
+                return Err(Diagnostic::error(
+                    "no `main` function found in the program",
+                    TLoc::empty(),
+                ));
             }
         };
 
-        self.call_function(main_func, vec![], None);
+        self.call_function(&main_func, vec![])?;
+        Ok(())
     }
 
-    fn collect_functions(&mut self, node: Rc<RefCell<MTree>>) {
-        if let TreeCode::FUNCTION = &node.borrow().token {
-            if let Some(name_node) = node.borrow().children.get(0) {
-                if let TreeCode::IDENTIFIER(name) = &name_node.borrow().token {
-                    self.functions.insert(name.clone(), node.clone());
-                }
-            }
-        }
-        for child in &node.borrow().children {
-            self.collect_functions(child.clone());
+    fn collect_functions(&mut self, program: &Program) {
+        for func in program.functions() {
+            self.functions.insert(func.name.name.clone(), Rc::new(func.clone()));
         }
     }
 
+    // Functions are scoped lexically: the new frame has no parent, so the body only sees
+    // its own parameters and locals (functions are resolved through `self.functions`),
+    // never the variables of whoever called it
     pub fn call_function(
         &self,
-        func_node: Rc<RefCell<MTree>>,
+        func: &Function,
         args: Vec<Value>,
-        parent_frame: Option<Rc<RefCell<Frame>>>
-    ) -> Value {
-
-        let frame = Rc::new(RefCell::new(Frame::new(parent_frame)));
-
-        if let Some(param_list) = func_node.borrow().children.get(1) {
-            for (i, p) in param_list.borrow().children.iter().enumerate() {
-                if let TreeCode::PARAMETER = &p.borrow().token {
-                    if let Some(id) = p.borrow().children.get(0) {
-                        if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                            frame.borrow_mut().variables.insert(name.clone(), args[i].clone());
-                        }
-                    }
-                }
-            }
+    ) -> RuntimeResult<Value> {
+
+        let frame = Rc::new(RefCell::new(Frame::new(None)));
+
+        for (p, arg) in func.params.iter().zip(args) {
+            frame.borrow_mut().declare(&p.name.name, Some(arg));
         }
 
-        let block = func_node.borrow().children.get(2).unwrap().clone();
-        self.run_block(block, frame).unwrap_or(Value::VOID)
+        Ok(self.run_block(&func.body, frame)?.unwrap_or(Value::VOID))
     }
 
-    fn run_block(&self, block: Rc<RefCell<MTree>>, frame: Rc<RefCell<Frame>>) -> Option<Value> {
-        for stmt in &block.borrow().children {
-            if let Some(val) = self.run_stmt(stmt.clone(), frame.clone()) {
-                return Some(val);
+    // Every block runs in its own frame chained to the enclosing one, mirroring the
+    // analyzer's ScopeStack: a `let` is visible until the closing "]" and may shadow outer names
+    fn run_block(&self, block: &Block, frame: Rc<RefCell<Frame>>) -> RuntimeResult<Option<Value>> {
+        let scope = Rc::new(RefCell::new(Frame::new(Some(frame))));
+        for stmt in &block.stmts {
+            if let Some(val) = self.run_stmt(stmt, scope.clone())? {
+                return Ok(Some(val));
             }
         }
-        None
+        Ok(None)
     }
 
-    fn run_stmt(&self, stmt: Rc<RefCell<MTree>>, frame: Rc<RefCell<Frame>>) -> Option<Value> {
-        match &stmt.borrow().token {
-
-            TreeCode::STATEMENT => {
-                if let Some(real_stmt) = stmt.borrow().children.get(0) {
-                    return self.run_stmt(real_stmt.clone(), frame);
-                }
-                None
+    fn run_stmt(&self, stmt: &Stmt, frame: Rc<RefCell<Frame>>) -> RuntimeResult<Option<Value>> {
+        match stmt {
+            Stmt::Block(block) => self.run_block(block, frame),
+            Stmt::Let { name, init, .. } => {
+                let init = match init {
+                    Some(expr) => Some(self.eval_expr(expr, frame.clone())?),
+                    None => None,
+                };
+                frame.borrow_mut().declare(&name.name, init);
+                Ok(None)
             }
-            TreeCode::BLOCK => self.run_block(stmt.clone(), frame),
-            TreeCode::LET => {
-                if let Some(id) = stmt.borrow().children.get(0) {
-                    if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                        frame.borrow_mut().variables.insert(name.clone(), Value::INT(0));
-                    }
-                }
-                None
+            Stmt::Assign { target, value, .. } => {
+                let val = self.eval_expr(value, frame.clone())?;
+                frame.borrow_mut().set(&target.name, val, &target.loc)?;
+                Ok(None)
             }
-            TreeCode::ASSIGN => {
-                let stmt_borrow = stmt.borrow();
-                let id = stmt_borrow.children.get(0).unwrap();
-                let expr = stmt_borrow.children.get(1).unwrap();
-                let val = self.eval_expr(expr.clone(), frame.clone());
-                if let TreeCode::IDENTIFIER(name) = &id.borrow().token {
-                    frame.borrow_mut().set(name, val);
-                }
-                None
+            Stmt::Print { value, .. } => {
+                let val = self.eval_expr(value, frame.clone())?;
+                println!("{}", val);
+                Ok(None)
             }
-            TreeCode::PRINT => {
-                let stmt_borrow = stmt.borrow();
-                let expr = stmt_borrow.children.get(0).unwrap();
-                let val = self.eval_expr(expr.clone(), frame.clone());
-                match val {
-                    Value::INT(i) => println!("{}", i),
-                    Value::VOID => println!("void"),
-                    _ => println!("unknown"),
+            Stmt::Return { value, .. } => Ok(Some(self.eval_expr(value, frame.clone())?)),
+            Stmt::If { cond, then_block, else_block, .. } => {
+                if self.eval_condition(cond, frame.clone(), "if")? {
+                    self.run_block(then_block, frame)
+                } else if let Some(else_block) = else_block {
+                    self.run_block(else_block, frame)
+                } else {
+                    Ok(None)
                 }
-                None
-            }
-            TreeCode::RETURN => {
-                let stmt_borrow = stmt.borrow();
-                let expr = stmt_borrow.children.get(0).unwrap();
-                Some(self.eval_expr(expr.clone(), frame.clone()))
-            }
-            TreeCode::IF => {
-                let stmt_borrow = stmt.borrow();
-                let cond_node = stmt_borrow.children.get(0)
-                    .expect("IF missing condition");
-                let cond_val = self.eval_expr(cond_node.clone(), frame.clone());
-                let branch_idx = if let Value::INT(i) = cond_val { i != 0 } else { false };
-                let block_node = stmt_borrow.children.get(if branch_idx { 1 } else { 2 })
-                    .expect("IF missing branch");
-                let block = self.unwrap_block(block_node.clone());
-                self.run_block(block, frame)
             }
-            TreeCode::WHILE => {
-                let stmt_borrow = stmt.borrow();
-                let cond = stmt_borrow.children.get(0).expect("WHILE missing condition");
-
-                let block = if let Some(body_node) = stmt_borrow.children.get(1) {
-
-                    match body_node.borrow().token {
-                        TreeCode::BLOCK | TreeCode::STATEMENT => self.unwrap_block(body_node.clone()),
-                        _ => {
-
-                            let b = MTree::new(TreeCode::BLOCK);
-                            b.borrow_mut().children.push(body_node.clone());
-                            b
-                        }
-                    }
-                } else {
-
-                    let b = MTree::new(TreeCode::BLOCK);
-                    b.borrow_mut().children.push(stmt.clone());
-                    b
-                };
-
-                loop {
-                    let cond_val = self.eval_expr(cond.clone(), frame.clone());
-                    if let Value::INT(i) = cond_val {
-                        if i == 0 { break; }
-                    } else {
-                        panic!("WHILE condition must evaluate to INT");
-                    }
+            Stmt::While { cond, body, .. } => {
+                while self.eval_condition(cond, frame.clone(), "while")? {
 
-                    if let Some(ret_val) = self.run_block(block.clone(), frame.clone()) {
-                        return Some(ret_val);
+                    if let Some(ret_val) = self.run_block(body, frame.clone())? {
+                        return Ok(Some(ret_val));
                     }
                 }
-                None
+                Ok(None)
             }
-            _ => None
+            // evaluated for its effects, so a call statement runs (and is arity-checked) too
+            Stmt::Expr { expr, .. } => {
+                self.eval_expr(expr, frame)?;
+                Ok(None)
+            }
+            Stmt::Empty { .. } => Ok(None),
+            Stmt::Error { loc } => Err(Diagnostic::error("cannot run a statement that failed to parse", loc.clone())),
         }
     }
 
-    fn unwrap_block(&self, stmt: Rc<RefCell<MTree>>) -> Rc<RefCell<MTree>> {
-        match stmt.borrow().token {
-            TreeCode::BLOCK => stmt.clone(),
-            TreeCode::STATEMENT => {
-                if let Some(child) = stmt.borrow().children.get(0) {
-                    self.unwrap_block(child.clone())
-                } else {
-                    panic!("STATEMENT node has no children")
+    // Conditions of `if` and `while` must be bools; there is no implicit truthiness for ints
+    fn eval_condition(&self, cond: &Expr, frame: Rc<RefCell<Frame>>, keyword: &str) -> RuntimeResult<bool> {
+        match self.eval_expr(cond, frame)? {
+            Value::BOOL(b) => Ok(b),
+            other => Err(runtime_error(
+                cond,
+                format!("`{}` condition must be a bool, found {}", keyword, other.type_name()),
+            )),
+        }
+    }
+
+    fn eval_expr(&self, expr: &Expr, frame: Rc<RefCell<Frame>>) -> RuntimeResult<Value> {
+        match expr {
+            Expr::Int { value, .. } => Ok(Value::INT(*value)),
+            Expr::Bool { value, .. } => Ok(Value::BOOL(*value)),
+            Expr::Str { value, .. } => Ok(Value::STR(value.clone())),
+            Expr::Var(ident) => frame.borrow().get(&ident.name, &ident.loc),
+            Expr::Unary { op, operand, .. } => self.eval_unary(expr, op, operand, frame),
+            Expr::Binary { op, left, right, .. } => self.eval_binary(expr, op, left, right, frame),
+            Expr::Call { name, args: arg_exprs, .. } => {
+                let name = &name.name;
+                let mut args = vec![];
+                for a in arg_exprs {
+                    args.push(self.eval_expr(a, frame.clone())?);
+                }
+                let func = match self.functions.get(name) {
+                    Some(f) => f.clone(),
+                    None => return Err(runtime_error(expr, format!("function `{}` not found", name))),
+                };
+                if args.len() != func.params.len() {
+                    return Err(runtime_error(
+                        expr,
+                        format!("wrong number of arguments to `{}`: expected {}, found {}", name, func.params.len(), args.len()),
+                    ));
                 }
+                self.call_function(&func, args)
             }
-            _ => panic!("Expected BLOCK or STATEMENT, found {:?}", stmt.borrow().token)
         }
     }
 
+    // unary = ( "!" | "-" ) unary
+    fn eval_unary(&self, expr: &Expr, op: &UnaryOp, operand: &Expr, frame: Rc<RefCell<Frame>>) -> RuntimeResult<Value> {
+        let operand = self.eval_expr(operand, frame)?;
+        match (op, operand) {
+            (UnaryOp::NEG, Value::INT(i)) => i.checked_neg()
+                .map(Value::INT)
+                .ok_or_else(|| runtime_error(expr, "integer overflow in `-`".to_string())),
+            (UnaryOp::NOT, Value::BOOL(b)) => Ok(Value::BOOL(!b)),
+            _ => Err(runtime_error(expr, format!("invalid operand for `{}`", op))),
+        }
+    }
 
+    fn eval_binary(&self, expr: &Expr, op: &BinaryOp, lhs: &Expr, rhs: &Expr, frame: Rc<RefCell<Frame>>) -> RuntimeResult<Value> {
+        use BinaryOp::*;
+        let left = self.eval_expr(lhs, frame.clone())?;
 
-    fn eval_expr(&self, expr: Rc<RefCell<MTree>>, frame: Rc<RefCell<Frame>>) -> Value {
-        match &expr.borrow().token {
-            TreeCode::INT_LITERAL(i) => Value::INT(*i),
-            TreeCode::IDENTIFIER(name) => frame.borrow().get(name).unwrap(),
-            TreeCode::OPERATOR(op) => {
-                let left = self.eval_expr(expr.borrow().children[0].clone(), frame.clone());
-                let right = self.eval_expr(expr.borrow().children[1].clone(), frame.clone());
-                if let (Value::INT(l), Value::INT(r)) = (left, right) {
-                    match op.as_str() {
-                        "+" => Value::INT(l + r),
-                        "-" => Value::INT(l - r),
-                        "*" => Value::INT(l * r),
-                        "<" => Value::INT((l < r) as i64),
-                        ">" => Value::INT((l > r) as i64),
-                        _ => panic!("Unsupported operator"),
-                    }
-                } else { panic!("Invalid operands") }
+        // "&" and "|" only evaluate the right side when the left does not decide the result
+        if let AND | OR = op {
+            let l = match left {
+                Value::BOOL(l) => l,
+                _ => return Err(runtime_error(expr, format!("invalid operands for `{}`", op))),
+            };
+            if (*op == AND && !l) || (*op == OR && l) {
+                return Ok(Value::BOOL(l));
             }
-            TreeCode::FUNCTION_CALL(_) => {
-                let expr_borrow = expr.borrow();
-                let name_node = expr_borrow.children.get(0).unwrap();
-                if let TreeCode::IDENTIFIER(name) = &name_node.borrow().token {
-                    let mut args = vec![];
-                    for a in expr.borrow().children.iter().skip(1) {
-                        args.push(self.eval_expr(a.clone(), frame.clone()));
-                    }
-                    let func_node = self.functions.get(name)
-                        .unwrap_or_else(|| panic!("Function `{}` not found", name));
-                    self.call_function(func_node.clone(), args, Some(frame.clone()))
-                } else { panic!("Expected function name") }
-            }
-            _ => panic!("Unsupported expression: {:?}", expr.borrow().token),
+            return match self.eval_expr(rhs, frame)? {
+                Value::BOOL(r) => Ok(Value::BOOL(r)),
+                _ => Err(runtime_error(expr, format!("invalid operands for `{}`", op))),
+            };
+        }
+
+        let right = self.eval_expr(rhs, frame)?;
+        let overflow = || runtime_error(expr, format!("integer overflow in `{}`", op));
+        match (op, left, right) {
+            (ADD, Value::INT(l), Value::INT(r)) => l.checked_add(r).map(Value::INT).ok_or_else(overflow),
+            (SUB, Value::INT(l), Value::INT(r)) => l.checked_sub(r).map(Value::INT).ok_or_else(overflow),
+            (MUL, Value::INT(l), Value::INT(r)) => l.checked_mul(r).map(Value::INT).ok_or_else(overflow),
+            (DIV, Value::INT(_), Value::INT(0)) => Err(runtime_error(expr, "division by zero".to_string())),
+            (DIV, Value::INT(l), Value::INT(r)) => l.checked_div(r).map(Value::INT).ok_or_else(overflow),
+            (LT, Value::INT(l), Value::INT(r)) => Ok(Value::BOOL(l < r)),
+            (GT, Value::INT(l), Value::INT(r)) => Ok(Value::BOOL(l > r)),
+            (EQUAL, Value::INT(l), Value::INT(r)) => Ok(Value::BOOL(l == r)),
+            (NOT_EQUAL, Value::INT(l), Value::INT(r)) => Ok(Value::BOOL(l != r)),
+            (EQUAL, Value::BOOL(l), Value::BOOL(r)) => Ok(Value::BOOL(l == r)),
+            (NOT_EQUAL, Value::BOOL(l), Value::BOOL(r)) => Ok(Value::BOOL(l != r)),
+
+            // string concatenation, an int or bool on either side is converted to text
+            (ADD, Value::STR(l), Value::STR(r)) => Ok(Value::STR(l + &r)),
+            (ADD, Value::STR(l), r @ (Value::INT(_) | Value::BOOL(_))) => Ok(Value::STR(format!("{}{}", l, r))),
+            (ADD, l @ (Value::INT(_) | Value::BOOL(_)), Value::STR(r)) => Ok(Value::STR(format!("{}{}", l, r))),
+            (EQUAL, Value::STR(l), Value::STR(r)) => Ok(Value::BOOL(l == r)),
+            (NOT_EQUAL, Value::STR(l), Value::STR(r)) => Ok(Value::BOOL(l != r)),
+
+            _ => Err(runtime_error(expr, format!("invalid operands for `{}`", op))),
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    // runs the program without the analyzer, which would reject these statically
+    fn run(src: &str) -> RuntimeResult<()> {
+        Runtime::new().run_program(&parse_valid(src))
+    }
+
+    // the value `main` returns, as it would be printed
+    fn main_returns(src: &str) -> String {
+        let mut runtime = Runtime::new();
+        runtime.collect_functions(&parse_valid(src));
+        let main = runtime.functions["main"].clone();
+        runtime.call_function(&main, vec![]).expect("main runs").to_string()
+    }
+
+    fn error_at(src: &str) -> (String, usize, usize) {
+        let err = run(src).unwrap_err();
+        (err.message, err.loc.first.row, err.loc.first.col)
+    }
+
+    #[test]
+    fn call_statement_is_arity_checked_at_runtime() {
+        let err = run("func inc(n) [ return n + 1; ] func main() [ inc(); ]").unwrap_err();
+        assert_eq!(err.message, "wrong number of arguments to `inc`: expected 1, found 0");
+        assert_eq!((err.loc.first.row, err.loc.first.col), (1, 45));
+    }
+
+    #[test]
+    fn call_statement_is_evaluated() {
+        let err = run("func fail(n) [ return n / 0; ] func main() [ fail(1); ]").unwrap_err();
+        assert_eq!(err.message, "division by zero");
+        assert!(run("func ok(n) [ return n; ] func main() [ ok(1); ]").is_ok());
+    }
+
+    #[test]
+    fn callee_cannot_see_the_callers_locals() {
+        assert_eq!(
+            error_at("func peek() [ print x; ] func main() [ let x = 1; peek(); ]"),
+            ("variable `x` used before declaration".to_string(), 1, 21),
+        );
+        assert_eq!(
+            error_at("func set() [ x = 2; ] func main() [ let x = 1; set(); ]"),
+            ("variable `x` used before declaration".to_string(), 1, 14),
+        );
+    }
+
+    #[test]
+    fn let_in_a_block_is_not_visible_after_it() {
+        assert_eq!(
+            error_at("func main() [ if true [ let y = 1; ] print y; ]"),
+            ("variable `y` used before declaration".to_string(), 1, 44),
+        );
+        assert_eq!(
+            error_at("func main() [ let i = 0; while i < 1 [ let y = i; i = i + 1; ] print y; ]"),
+            ("variable `y` used before declaration".to_string(), 1, 70),
+        );
+    }
+
+    #[test]
+    fn shadowed_name_keeps_its_outer_value() {
+        assert_eq!(main_returns("func main() [ let x = 1; if true [ let x = 2; x = 3; ] return x; ]"), "1");
+        assert_eq!(main_returns("func main() [ let x = 1; let i = 0; while i < 2 [ let x = 5; i = i + 1; ] return x; ]"), "1");
+        // without a `let`, the block assigns the outer variable
+        assert_eq!(main_returns("func main() [ let x = 1; if true [ x = 3; ] return x; ]"), "3");
+    }
+}
@@ -0,0 +1,155 @@
+use crate::ast::{Program, Function, Block, Stmt, Expr};
+use crate::tokens::TLoc;
+use crate::diagnostics::Diagnostic;
+use crate::visit::{self, Visitor};
+
+// control-flow checks
+// Warns about functions that can fall off the end after returning a value on other paths
+// (the call then silently yields void) and about statements that can never run.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    for func in program.functions() {
+        check_function(func, &mut diags);
+    }
+    diags
+}
+
+fn check_function(func: &Function, diags: &mut Vec<Diagnostic>) {
+    let name = &func.name.name;
+    let declared = func.ret.as_ref().filter(|ty| ty.name != "void");
+
+    Reachability { diags: &mut *diags }.visit_block(&func.body);
+
+    let mut returns = Returns::default();
+    returns.visit_block(&func.body);
+    let returns = returns.locs;
+    if block_falls_through(&func.body) && (declared.is_some() || !returns.is_empty()) {
+        let mut diag = Diagnostic::warning(
+            format!("function `{}` can reach the end without returning a value", name),
+            func.name.loc.clone(),
+        );
+        diag = match (declared, returns.first()) {
+            (Some(ty), _) => diag.with_note(format!("`{}` is declared to return {}", name, ty.name)),
+            (None, Some(loc)) => diag.with_note_at("a value is returned here", loc.clone()),
+            (None, None) => diag,
+        };
+        diags.push(diag.with_note("calls that reach the end produce `void`"));
+    }
+}
+
+fn block_falls_through(block: &Block) -> bool {
+    block.stmts.iter().all(falls_through)
+}
+
+// Can control get past the end of this statement?
+fn falls_through(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } => false,
+        Stmt::Block(block) => block_falls_through(block),
+        Stmt::If { then_block, else_block: Some(else_block), .. } =>
+            block_falls_through(then_block) || block_falls_through(else_block),
+        // without `break`, `while true` can only be left through a return
+        Stmt::While { cond, .. } => !matches!(cond, Expr::Bool { value: true, .. }),
+        _ => true,
+    }
+}
+
+// Flags the first statement in each block that follows one which never completes
+struct Reachability<'a> {
+    diags: &'a mut Vec<Diagnostic>,
+}
+
+impl Visitor<'_> for Reachability<'_> {
+    fn visit_block(&mut self, block: &Block) {
+        let mut exit: Option<&TLoc> = None;
+        for stmt in &block.stmts {
+            let is_empty = matches!(stmt, Stmt::Empty { .. } | Stmt::Error { .. });
+            if let Some(exit_loc) = exit {
+                if !is_empty {
+                    self.diags.push(
+                        Diagnostic::warning("unreachable statement", stmt.loc().clone())
+                            .with_note_at("any code following this statement is unreachable", exit_loc.clone()),
+                    );
+                    break;
+                }
+            } else if !falls_through(stmt) {
+                exit = Some(stmt.loc());
+            }
+        }
+        visit::walk_block(self, block);
+    }
+}
+
+// every `return` in a function body, in source order
+#[derive(Default)]
+struct Returns {
+    locs: Vec<TLoc>,
+}
+
+impl Visitor<'_> for Returns {
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        if let Stmt::Return { loc, .. } = stmt {
+            self.locs.push(loc.clone());
+        }
+        visit::walk_stmt(self, stmt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    fn warnings(src: &str) -> Vec<Diagnostic> {
+        check(&parse_valid(src))
+    }
+
+    fn at(loc: &TLoc) -> (usize, usize) {
+        (loc.first.row, loc.first.col)
+    }
+
+    #[test]
+    fn declared_function_that_can_fall_off_the_end_is_reported() {
+        let diags = warnings("func f(x: int) -> int [\n    if x > 0 [ return 1; ]\n]");
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "function `f` can reach the end without returning a value");
+        assert_eq!(at(&diags[0].loc), (1, 6));
+        let notes: Vec<&str> = diags[0].notes.iter().map(|n| n.message.as_str()).collect();
+        assert_eq!(notes, ["`f` is declared to return int", "calls that reach the end produce `void`"]);
+    }
+
+    #[test]
+    fn undeclared_function_that_sometimes_returns_a_value_is_reported() {
+        let diags = warnings("func f(x) [\n    if x > 0 [ return 1; ]\n    print x;\n]");
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "function `f` can reach the end without returning a value");
+        assert_eq!(diags[0].notes[0].message, "a value is returned here");
+        assert_eq!(at(diags[0].notes[0].loc.as_ref().unwrap()), (2, 16));
+    }
+
+    #[test]
+    fn functions_that_always_return_or_never_do_are_accepted() {
+        assert!(warnings("func f(x) -> int [ if x > 0 [ return 1; ] else [ return 2; ] ]").is_empty());
+        assert!(warnings("func f() -> int [ while true [ return 1; ] ]").is_empty());
+        assert!(warnings("func f(x) [ print x; ]").is_empty());
+    }
+
+    #[test]
+    fn first_statement_after_a_return_is_unreachable() {
+        let diags = warnings("func f() -> int [\n    return 1;\n    print 2;\n    print 3;\n]");
+        assert_eq!(diags.len(), 1);
+        assert_eq!(diags[0].message, "unreachable statement");
+        assert_eq!(at(&diags[0].loc), (3, 5));
+        assert_eq!(diags[0].notes[0].message, "any code following this statement is unreachable");
+        assert_eq!(at(diags[0].notes[0].loc.as_ref().unwrap()), (2, 5));
+    }
+
+    #[test]
+    fn statement_after_an_if_whose_branches_both_return_is_unreachable() {
+        let diags = warnings("func f(x) -> int [\n    if x [ return 1; ] else [ return 2; ]\n    ;\n    print x;\n]");
+        assert_eq!(diags.len(), 1);
+        // the empty statement is skipped over
+        assert_eq!(at(&diags[0].loc), (4, 5));
+        assert_eq!(at(diags[0].notes[0].loc.as_ref().unwrap()), (2, 5));
+    }
+}
@@ -1,10 +1,12 @@
 use crate::tokens::{TCode, Token, TLoc, TPos};
+use crate::diagnostics::Diagnostic;
 
 pub struct Lexer {
     src: Vec<char>,
     pub(crate) pos: usize,
     pub(crate) row: usize,
     pub(crate) col: usize,
+    pub(crate) errors: Vec<Diagnostic>,
 }
 
 impl Lexer {
@@ -14,6 +16,7 @@ impl Lexer {
             pos: 0,
             row: 1,
             col: 1,
+            errors: vec![],
         }
     }
 
@@ -43,16 +46,64 @@ impl Lexer {
         }
     }
 
+    fn peek_second(&self) -> Option<char> {
+        self.src.get(self.pos + 1).cloned()
+    }
+
+    // Skips whitespace and comments:
+    // "//" runs to the end of the line, "/* ... */" may span lines and nest
     fn skip_whitespace(&mut self) {
         while let Some(c) = self.peek() {
             if c == ' ' || c == '\t' || c == '\r' || c == '\n' {
                 self.advance();
+            } else if c == '/' && self.peek_second() == Some('/') {
+                while let Some(c) = self.peek() {
+                    if c == '\n' {
+                        break;
+                    }
+                    self.advance();
+                }
+            } else if c == '/' && self.peek_second() == Some('*') {
+                self.skip_block_comment();
             } else {
                 break;
             }
         }
     }
 
+    fn skip_block_comment(&mut self) {
+        let sr = self.row;
+        let sc = self.col;
+        self.advance();
+        self.advance();
+
+        let mut depth = 1;
+        while depth > 0 {
+            match (self.peek(), self.peek_second()) {
+                (Some('/'), Some('*')) => {
+                    self.advance();
+                    self.advance();
+                    depth += 1;
+                }
+                (Some('*'), Some('/')) => {
+                    self.advance();
+                    self.advance();
+                    depth -= 1;
+                }
+                (Some(_), _) => {
+                    self.advance();
+                }
+                (None, _) => {
+                    self.errors.push(Diagnostic::error(
+                        "unterminated block comment",
+                        TLoc { first: TPos::new(sr, sc, 2), last: TPos::new(sr, sc + 1, 1) },
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+
     // FSM states for identifiers/keywords
     fn scan_ident_or_keyword(&mut self) -> Token {
         let sr = self.row;
@@ -89,13 +140,14 @@ impl Lexer {
     }
 
     // FSM for integers
+    // decimal, 0x hex or 0b binary, with optional "_" separators (1_000_000)
     fn scan_int(&mut self) -> Token {
         let sr = self.row;
         let sc = self.col;
 
         let mut buf = String::new();
         while let Some(c) = self.peek() {
-            if c.is_ascii_digit() {
+            if c.is_ascii_alphanumeric() || c == '_' {
                 buf.push(c);
                 self.advance();
             } else {
@@ -103,10 +155,104 @@ impl Lexer {
             }
         }
 
-        let value = buf.parse::<i64>().unwrap();
         let len = self.col - sc;
+        let loc = self.make_loc(sr, sc, len);
+
+        let (radix, kind, digits) = match buf.get(..2) {
+            Some("0x") | Some("0X") => (16, "hexadecimal", &buf[2..]),
+            Some("0b") | Some("0B") => (2, "binary", &buf[2..]),
+            _ => (10, "decimal", &buf[..]),
+        };
+        let digits: String = digits.chars().filter(|c| *c != '_').collect();
+
+        // a bad literal is reported and stands in as 0 so parsing can go on
+        let value = if digits.is_empty() {
+            self.errors.push(Diagnostic::error(
+                format!("{} literal `{}` has no digits", kind, buf),
+                loc.clone(),
+            ));
+            0
+        } else if let Some(bad) = digits.chars().find(|c| !c.is_digit(radix)) {
+            self.errors.push(Diagnostic::error(
+                format!("invalid digit `{}` in {} literal `{}`", bad, kind, buf),
+                loc.clone(),
+            ));
+            0
+        } else {
+            match i64::from_str_radix(&digits, radix) {
+                Ok(value) => value,
+                Err(_) => {
+                    self.errors.push(
+                        Diagnostic::error(
+                            format!("integer literal `{}` is out of range", buf),
+                            loc.clone(),
+                        )
+                        .with_note(format!("the largest integer is {}", i64::MAX)),
+                    );
+                    0
+                }
+            }
+        };
+
         Token {
             code: TCode::INT(value),
+            loc,
+        }
+    }
+
+    // FSM for string literals
+    // "..." on a single line, with escapes \n \t \r \0 \\ and \"
+    fn scan_string(&mut self) -> Token {
+        let sr = self.row;
+        let sc = self.col;
+        self.advance();
+
+        let mut buf = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.advance();
+                    break;
+                }
+                Some('\\') => {
+                    let er = self.row;
+                    let ec = self.col;
+                    self.advance();
+                    match self.peek() {
+                        Some('n') => buf.push('\n'),
+                        Some('t') => buf.push('\t'),
+                        Some('r') => buf.push('\r'),
+                        Some('0') => buf.push('\0'),
+                        Some('\\') => buf.push('\\'),
+                        Some('"') => buf.push('"'),
+                        Some(c) if c != '\n' => {
+                            self.errors.push(Diagnostic::error(
+                                format!("unknown escape sequence `\\{}`", c),
+                                TLoc { first: TPos::new(er, ec, 2), last: TPos::new(er, ec + 1, 1) },
+                            ));
+                        }
+                        // a backslash at the end of the line is left for the unterminated check
+                        _ => continue,
+                    }
+                    self.advance();
+                }
+                Some(c) if c != '\n' => {
+                    buf.push(c);
+                    self.advance();
+                }
+                _ => {
+                    self.errors.push(Diagnostic::error(
+                        "unterminated string literal",
+                        TLoc { first: TPos::new(sr, sc, 1), last: TPos::new(sr, sc, 1) },
+                    ));
+                    break;
+                }
+            }
+        }
+
+        let len = if self.row == sr { self.col - sc } else { 1 };
+        Token {
+            code: TCode::STRING(buf),
             loc: self.make_loc(sr, sc, len),
         }
     }
@@ -122,72 +268,95 @@ impl Lexer {
     }
 
     pub fn next_token(&mut self) -> Token {
-        self.skip_whitespace();
-
-        let sr = self.row;
-        let sc = self.col;
-
-        let ch = match self.advance() {
-            Some(c) => c,
-            None => return Token::from(TCode::EOI),
-        };
-
-        // Identifiers and keywords
-        if ch.is_ascii_alphabetic() || ch == '_' {
-            // backtrack one character
-            self.pos -= 1;
-            self.col -= 1;
-            return self.scan_ident_or_keyword();
-        }
-
-        // Integers
-        if ch.is_ascii_digit() {
-            // backtrack one character
-            self.pos -= 1;
-            self.col -= 1;
-            return self.scan_int();
-        }
-
-        // Operators / punctuation
-        match ch {
-            '=' => {
-                if let Some(tok) = self.two_char_operator('=', TCode::OP_EQUAL) {
-                    return Token { code: tok, loc: self.make_loc(sr, sc, 2) };
-                }
-                return Token { code: TCode::OP_ASSIGN, loc: self.make_loc(sr, sc, 1) };
+        // unknown characters are reported and skipped, then scanning carries on
+        loop {
+            self.skip_whitespace();
+
+            let sr = self.row;
+            let sc = self.col;
+
+            let ch = match self.advance() {
+                Some(c) => c,
+                None => return Token { code: TCode::EOI, loc: self.make_loc(sr, sc, 0) },
+            };
+
+            // Identifiers and keywords
+            if ch.is_ascii_alphabetic() || ch == '_' {
+                // backtrack one character
+                self.pos -= 1;
+                self.col -= 1;
+                return self.scan_ident_or_keyword();
             }
 
-            '!' => {
-                if let Some(tok) = self.two_char_operator('=', TCode::OP_NOT_EQUAL) {
-                    return Token { code: tok, loc: self.make_loc(sr, sc, 2) };
-                }
-                return Token { code: TCode::OP_NOT, loc: self.make_loc(sr, sc, 1) };
+            // Integers
+            if ch.is_ascii_digit() {
+                // backtrack one character
+                self.pos -= 1;
+                self.col -= 1;
+                return self.scan_int();
             }
 
-            '<' => return Token { code: TCode::OP_LT, loc: self.make_loc(sr, sc, 1) },
-            '>' => return Token { code: TCode::OP_GT, loc: self.make_loc(sr, sc, 1) },
-
-            '+' => return Token { code: TCode::OP_ADD, loc: self.make_loc(sr, sc, 1) },
-            '-' => return Token { code: TCode::OP_SUB, loc: self.make_loc(sr, sc, 1) },
-            '*' => return Token { code: TCode::OP_MUL, loc: self.make_loc(sr, sc, 1) },
-            '/' => return Token { code: TCode::OP_DIV, loc: self.make_loc(sr, sc, 1) },
+            // Strings
+            if ch == '"' {
+                // backtrack one character
+                self.pos -= 1;
+                self.col -= 1;
+                return self.scan_string();
+            }
 
-            '&' => return Token { code: TCode::OP_AND, loc: self.make_loc(sr, sc, 1) },
-            '|' => return Token { code: TCode::OP_OR, loc: self.make_loc(sr, sc, 1) },
+            // Operators / punctuation
+            match ch {
+                '=' => {
+                    if let Some(tok) = self.two_char_operator('=', TCode::OP_EQUAL) {
+                        return Token { code: tok, loc: self.make_loc(sr, sc, 2) };
+                    }
+                    return Token { code: TCode::OP_ASSIGN, loc: self.make_loc(sr, sc, 1) };
+                }
 
-            '(' => return Token { code: TCode::PAREN_L, loc: self.make_loc(sr, sc, 1) },
-            ')' => return Token { code: TCode::PAREN_R, loc: self.make_loc(sr, sc, 1) },
-            '[' => return Token { code: TCode::BRACKET_L, loc: self.make_loc(sr, sc, 1) },
-            ']' => return Token { code: TCode::BRACKET_R, loc: self.make_loc(sr, sc, 1) },
+                '!' => {
+                    if let Some(tok) = self.two_char_operator('=', TCode::OP_NOT_EQUAL) {
+                        return Token { code: tok, loc: self.make_loc(sr, sc, 2) };
+                    }
+                    return Token { code: TCode::OP_NOT, loc: self.make_loc(sr, sc, 1) };
+                }
 
-            ';' => return Token { code: TCode::SEMICOLON, loc: self.make_loc(sr, sc, 1) },
-            ',' => return Token { code: TCode::COMMA, loc: self.make_loc(sr, sc, 1) },
+                '<' => return Token { code: TCode::OP_LT, loc: self.make_loc(sr, sc, 1) },
+                '>' => return Token { code: TCode::OP_GT, loc: self.make_loc(sr, sc, 1) },
 
-            _ => return Token::error(),
+                '+' => return Token { code: TCode::OP_ADD, loc: self.make_loc(sr, sc, 1) },
+                '-' => {
+                    if let Some(tok) = self.two_char_operator('>', TCode::ARROW) {
+                        return Token { code: tok, loc: self.make_loc(sr, sc, 2) };
+                    }
+                    return Token { code: TCode::OP_SUB, loc: self.make_loc(sr, sc, 1) };
+                }
+                '*' => return Token { code: TCode::OP_MUL, loc: self.make_loc(sr, sc, 1) },
+                '/' => return Token { code: TCode::OP_DIV, loc: self.make_loc(sr, sc, 1) },
+
+                '&' => return Token { code: TCode::OP_AND, loc: self.make_loc(sr, sc, 1) },
+                '|' => return Token { code: TCode::OP_OR, loc: self.make_loc(sr, sc, 1) },
+
+                '(' => return Token { code: TCode::PAREN_L, loc: self.make_loc(sr, sc, 1) },
+                ')' => return Token { code: TCode::PAREN_R, loc: self.make_loc(sr, sc, 1) },
+                '[' => return Token { code: TCode::BRACKET_L, loc: self.make_loc(sr, sc, 1) },
+                ']' => return Token { code: TCode::BRACKET_R, loc: self.make_loc(sr, sc, 1) },
+
+                ';' => return Token { code: TCode::SEMICOLON, loc: self.make_loc(sr, sc, 1) },
+                ',' => return Token { code: TCode::COMMA, loc: self.make_loc(sr, sc, 1) },
+                ':' => return Token { code: TCode::COLON, loc: self.make_loc(sr, sc, 1) },
+
+                _ => {
+                    self.errors.push(Diagnostic::error(
+                        format!("unknown character `{}`", ch),
+                        self.make_loc(sr, sc, 1),
+                    ));
+                }
+            }
         }
     }
 
-    pub fn tokenize(&mut self) -> Vec<Token> {
+    // Scan the whole input, returning the tokens together with any lexical errors found
+    pub fn tokenize(&mut self) -> (Vec<Token>, Vec<Diagnostic>) {
         let mut out = Vec::new();
 
         loop {
@@ -199,7 +368,80 @@ impl Lexer {
             out.push(t);
         }
 
-        out
+        (out, std::mem::take(&mut self.errors))
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every token but the final EOI, as the tokenize command prints it
+    fn codes(src: &str) -> Vec<String> {
+        let (tokens, _) = Lexer::new(src).tokenize();
+        tokens.iter().filter(|t| !matches!(t.code, TCode::EOI)).map(|t| format!("{:?}", t.code)).collect()
+    }
+
+    fn errors(src: &str) -> Vec<(String, usize, usize)> {
+        let (_, errors) = Lexer::new(src).tokenize();
+        errors.into_iter().map(|e| (e.message, e.loc.first.row, e.loc.first.col)).collect()
+    }
+
+    #[test]
+    fn largest_integer_is_accepted_and_the_next_one_is_out_of_range() {
+        assert_eq!(codes("9223372036854775807"), ["INT(9223372036854775807)"]);
+        assert_eq!(errors("9223372036854775807"), []);
+        // the bad literal stands in as 0
+        assert_eq!(codes("print 9223372036854775808;"), ["PRINT", "INT(0)", ";"]);
+        assert_eq!(
+            errors("print 9223372036854775808;"),
+            [("integer literal `9223372036854775808` is out of range".to_string(), 1, 7)],
+        );
+        assert_eq!(errors("0x8000000000000000")[0].0, "integer literal `0x8000000000000000` is out of range");
+    }
+
+    #[test]
+    fn malformed_literals_are_reported() {
+        assert_eq!(errors("0x"), [("hexadecimal literal `0x` has no digits".to_string(), 1, 1)]);
+        assert_eq!(errors("1 + 0b102"), [("invalid digit `2` in binary literal `0b102`".to_string(), 1, 5)]);
+        assert_eq!(codes("1 + 0b102"), ["INT(1)", "+", "INT(0)"]);
+    }
+
+    #[test]
+    fn underscores_separate_digits() {
+        assert_eq!(codes("1_000 0x_ff 0b1010_1010"), ["INT(1000)", "INT(255)", "INT(170)"]);
+        assert_eq!(errors("1_000 0x_ff 0b1010_1010"), []);
+    }
+
+    #[test]
+    fn block_comments_nest() {
+        assert_eq!(codes("1 /* a /* b */ c */ 2"), ["INT(1)", "INT(2)"]);
+        assert_eq!(codes("1 // 2 /* 3\n4"), ["INT(1)", "INT(4)"]);
+    }
+
+    #[test]
+    fn unterminated_comment_is_reported_at_its_opening() {
+        // the `*` of `/*` cannot also start the closing `*/`
+        assert_eq!(codes("x /*/ 1"), ["ID(\"x\")"]);
+        assert_eq!(errors("x /*/ 1"), [("unterminated block comment".to_string(), 1, 3)]);
+        assert_eq!(errors("1\n  /* open /* nested */\n2"), [("unterminated block comment".to_string(), 2, 3)]);
+    }
+
+    #[test]
+    fn escape_sequences_are_decoded() {
+        let expected = format!("STRING({:?})", "a\n\t\r\0\\\"b");
+        assert_eq!(codes(r#""a\n\t\r\0\\\"b""#), [expected]);
+        assert_eq!(codes(r#"print "a\qb";"#), ["PRINT", "STRING(\"ab\")", ";"]);
+        assert_eq!(errors(r#"print "a\qb";"#), [("unknown escape sequence `\\q`".to_string(), 1, 9)]);
+    }
+
+    #[test]
+    fn unterminated_string_is_reported_at_its_opening_quote() {
+        // the string ends with the line, so the next line is scanned as usual
+        assert_eq!(codes("\"abc\nprint"), ["STRING(\"abc\")", "PRINT"]);
+        assert_eq!(errors("\"abc\nprint"), [("unterminated string literal".to_string(), 1, 1)]);
+        assert_eq!(errors("x = \"abc\\\n"), [("unterminated string literal".to_string(), 1, 5)]);
+        assert_eq!(errors("x = \"abc"), [("unterminated string literal".to_string(), 1, 5)]);
+    }
+}
@@ -1,16 +1,25 @@
 
 mod tokens;
+mod diagnostics;
 mod lexer;
 mod parser;
 mod descent_parser;
+mod ast;
+mod visit;
 mod mtree;
+mod serialize;
+mod dot;
+mod cfg;
 mod analyzer;
+mod typechecker;
+mod flow;
 mod evaluator;
 
 use std::env;
 use std::fs;
+use std::process::ExitCode;
 
-fn main() {
+fn main() -> ExitCode {
     // user input should look like:
     // cargo run [command] [file]
 
@@ -24,7 +33,7 @@ fn main() {
         Some(arg) => arg,
         None => {
             println!("No command provided. Use 'help' to see available commands.");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
@@ -32,11 +41,15 @@ fn main() {
     match function.as_str() {
         "help" => help(terminal_input),
         "list" => list_commands(),
-        "tokenize" => configure_lexer(terminal_input),
-        "parse" => parse_file(terminal_input),
-        "execute" => execute(terminal_input),
-        _ => println!("Unknown command: {function}")
+        "tokenize" => return configure_lexer(terminal_input),
+        "parse" => return parse_file(terminal_input),
+        "execute" => return execute(terminal_input),
+        _ => {
+            println!("Unknown command: {function}");
+            return ExitCode::FAILURE;
+        }
     }
+    ExitCode::SUCCESS
 }
 
 // The help command displays a help message to clarify each command
@@ -56,9 +69,15 @@ fn help(args: Vec<String>) {
             }
             "[parse]" => {
                 println!("parse                         Parse a given input file and print the resulting parse tree.");
+                println!("parse [file] --json           Print the parse tree as JSON, with node kinds, values, spans and children.");
+                println!("parse [file] --sexpr          Print the parse tree as an S-expression.");
+                println!("parse [file] --from-json      Read a tree written by --json instead of source code.");
+                println!("parse [file] --dot            Print the parse tree as a Graphviz DOT graph.");
+                println!("parse [file] --cfg            Print the control-flow graph of each function as a Graphviz DOT graph.");
             }
             "[execute]" => {
                 println!("execute                       Execute a given input file and print the tree and the result of the program.");
+                println!("execute [file] --allow-shadowing  Do not warn when a declaration hides an outer variable or parameter.");
             }
             _ => println!("Unknown command: {command}"),
         }
@@ -85,61 +104,118 @@ fn list_commands() {
 }
 
 // The configure lexer method is called for the tokenize command to create a new lexer from the file input for analysis
-fn configure_lexer(args: Vec<String>) {
+fn configure_lexer(args: Vec<String>) -> ExitCode {
     let file_path = match args.get(2) {
         Some(path) => path,
         None => {
             println!("No file specified.");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
     let contents = fs::read_to_string(file_path).expect("Something went wrong reading the file");
     let mut lex = lexer::Lexer::new(&*contents);
-    loop {
-        let tok = lex.next_token();
+    let (tokens, errors) = lex.tokenize();
+    for tok in &tokens {
         println!("{:?}", tok.code);
-        if matches!(tok.code, tokens::TCode::EOI) {
-            break;
-        }
     }
+    report(file_path, &contents, &errors)
 }
 
-fn parse_file(args: Vec<String>) {
+fn parse_file(args: Vec<String>) -> ExitCode {
     let file_path = match args.get(2) {
         Some(path) => path,
         None => {
             println!("No file specified.");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
+    let from_json = args.iter().any(|arg| arg == "--from-json");
+    let show_cfg = args.iter().any(|arg| arg == "--cfg");
+    if from_json && show_cfg {
+        println!("The control-flow graph is built from source code, not from a JSON tree.");
+        return ExitCode::FAILURE;
+    }
+
     let contents = fs::read_to_string(file_path).expect("Something went wrong reading the file");
-    let lexer = lexer::Lexer::new(&*contents);
-    let mut parser = parser::Parser::new(lexer);
-    let ast = parser.parse();
-    println!("--- AST (MTree) ---");
-    ast.borrow().print();
+    let mut status = ExitCode::SUCCESS;
+    let tree = if from_json {
+        match mtree::MTree::from_json(&contents) {
+            Ok(tree) => tree,
+            Err(diag) => {
+                return report(file_path, &contents, &[diag]);
+            }
+        }
+    } else {
+        let lexer = lexer::Lexer::new(&*contents);
+        let mut parser = parser::Parser::new(lexer);
+        let (program, syntax_errors) = parser.parse();
+        status = report(file_path, &contents, &syntax_errors);
+        if show_cfg {
+            print!("{}", cfg::to_dot(&program));
+            return status;
+        }
+        mtree::MTree::lower(&program)
+    };
+    // the machine-readable forms go to stdout on their own, so they can be piped into other tools
+    if args.iter().any(|arg| arg == "--json") {
+        print!("{}", tree.borrow().to_json());
+    } else if args.iter().any(|arg| arg == "--sexpr") {
+        print!("{}", tree.borrow().to_sexpr());
+    } else if args.iter().any(|arg| arg == "--dot") {
+        print!("{}", tree.borrow().to_dot());
+    } else {
+        println!("--- AST (MTree) ---");
+        tree.borrow().print();
+    }
+    status
 }
 
-pub fn execute(args: Vec<String>) {
+pub fn execute(args: Vec<String>) -> ExitCode {
     let file_path = match args.get(2) {
         Some(path) => path,
         None => {
             println!("No file specified.");
-            return;
+            return ExitCode::FAILURE;
         }
     };
     let contents = fs::read_to_string(file_path).expect("Something went wrong reading the file");
     let lexer = lexer::Lexer::new(&*contents);
     let mut parser = parser::Parser::new(lexer);
-    let ast = parser.parse();
+    let (program, syntax_errors) = parser.parse();
+    report(file_path, &contents, &syntax_errors);
+
+    // the analyzer still runs over the valid parts of a partial tree, but it is never executed
     println!("--- ANALYZING ---");
-    analyzer::analyze(ast.clone());
+    let mut diags = Vec::new();
+    let options = analyzer::Options {
+        warn_shadowing: !args.iter().any(|arg| arg == "--allow-shadowing"),
+    };
+    diags.extend(analyzer::analyze(&program, &options));
+    diags.extend(typechecker::check(&program));
+    diags.extend(flow::check(&program));
+    report(file_path, &contents, &diags);
+    // warnings are reported but do not stop the program from running
+    if diagnostics::has_errors(&diags) || !syntax_errors.is_empty() {
+        return ExitCode::FAILURE;
+    }
 
     println!("--- RUNNING PROGRAM ---");
     let mut runtime = evaluator::Runtime::new();
-    runtime.run_program(ast.clone());
+    if let Err(diag) = runtime.run_program(&program) {
+        return report(file_path, &contents, &[diag]);
+    }
 
     println!("--- DONE ---\n");
-}
\ No newline at end of file
+    ExitCode::SUCCESS
+}
+
+// Print every collected diagnostic to stderr, annotated with the source it points at;
+// the exit status is a failure if any of them is an error
+fn report(path: &str, src: &str, diags: &[diagnostics::Diagnostic]) -> ExitCode {
+    for diag in diags {
+        eprintln!("{}", diag.render(path, src));
+    }
+    if diagnostics::has_errors(diags) { ExitCode::FAILURE } else { ExitCode::SUCCESS }
+}
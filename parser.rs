@@ -1,12 +1,18 @@
 use crate::lexer::Lexer;
-use crate::tokens::{TCode, Token};
+use crate::tokens::{TCode, Token, TLoc};
+use crate::diagnostics::Diagnostic;
 
 const INDENT : usize = 2;
 
+pub type ParseResult<T> = Result<T, Diagnostic>;
+
 pub struct Parser {
     lexer: Lexer,
     current_token: Token,
+    // location of the last token consumed, where the node being built ends
+    previous_loc: TLoc,
     indent: usize,
+    pub(crate) diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
@@ -16,7 +22,9 @@ impl Parser {
         let parser = Self {
             lexer,
             current_token : first,
+            previous_loc: TLoc::empty(),
             indent: 0,
+            diagnostics: vec![],
         };
         parser
     }
@@ -25,8 +33,22 @@ impl Parser {
         &self.current_token.code
     }
 
+    pub fn curr_loc(&self) -> TLoc {
+        self.current_token.loc.clone()
+    }
+
+    pub fn prev_loc(&self) -> TLoc {
+        self.previous_loc.clone()
+    }
+
     pub fn advance(&mut self) {
-        self.current_token = self.lexer.next_token();
+        let next = self.lexer.next_token();
+        self.previous_loc = std::mem::replace(&mut self.current_token, next).loc;
+    }
+
+    // span of a node that started at `start` and ends with the last consumed token
+    pub fn span_from(&self, start: &TLoc) -> TLoc {
+        start.to(&self.previous_loc)
     }
 
     pub fn peek(&mut self, symbol: &TCode) -> bool {
@@ -37,43 +59,61 @@ impl Parser {
         let saved_pos = self.lexer.pos;
         let saved_row = self.lexer.row;
         let saved_col = self.lexer.col;
+        let saved_errors = self.lexer.errors.len();
 
         let next_token = self.lexer.next_token();
 
+        // the token will be scanned again, so drop anything reported while looking ahead
         self.lexer.pos = saved_pos;
         self.lexer.row = saved_row;
         self.lexer.col = saved_col;
+        self.lexer.errors.truncate(saved_errors);
 
         next_token
     }
 
 
-    pub fn expect(&mut self, symbol: TCode) {
+    pub fn expect(&mut self, symbol: TCode) -> ParseResult<()> {
         let curr_token = self.curr();
 
         if curr_token == &symbol {
             //println!("{:<indent$}expect({:?})", "", symbol, indent = self.indent);
             self.advance();
+            Ok(())
         } else {
-            panic!(
-                "Syntax error: expected {:?}, but found {:?}",
-                symbol, curr_token
-            );
+            Err(self.unexpected(&format!("`{:?}`", symbol)))
         }
     }
 
-    pub fn expect_id(&mut self) -> String {
+    pub fn expect_id(&mut self) -> ParseResult<(String, TLoc)> {
 
         match self.curr() {
             TCode::ID(name) => {
                 let out = name.clone();
+                let loc = self.curr_loc();
                 self.advance();
-                out
+                Ok((out, loc))
             }
-            other => panic!("Expected identifier, found {:?}", other),
+            _ => Err(self.unexpected("identifier")),
         }
     }
 
+    // Builds the "expected X, but found Y" syntax error at the current token
+    pub fn unexpected(&self, expected: &str) -> Diagnostic {
+        Diagnostic::error(
+            format!("expected {}, but found `{:?}`", expected, self.curr()),
+            self.curr_loc(),
+        )
+    }
+
+    // Lexical and syntax errors collected so far, in source order
+    pub(crate) fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        let mut diags = std::mem::take(&mut self.lexer.errors);
+        diags.append(&mut self.diagnostics);
+        diags.sort_by_key(|d| (d.loc.first.row, d.loc.first.col));
+        diags
+    }
+
     pub fn accept(&mut self, symbol: TCode) -> bool {
         if self.curr() == &symbol {
             self.advance();
@@ -93,4 +133,11 @@ impl Parser {
     pub(crate) fn indent_decrement(&mut self) {
         self.indent -= INDENT;
     }
-}
\ No newline at end of file
+}
+// Parses a test program that is expected to be free of syntax errors
+#[cfg(test)]
+pub(crate) fn parse_valid(src: &str) -> crate::ast::Program {
+    let (program, errors) = Parser::new(Lexer::new(src)).parse();
+    assert!(errors.is_empty(), "unexpected syntax errors in {:?}", src);
+    program
+}
@@ -0,0 +1,570 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use crate::mtree::MTree;
+use crate::tokens::{TreeCode, TLoc, TPos, BinaryOp, UnaryOp};
+use crate::diagnostics::Diagnostic;
+use crate::visit::{self, TreeVisitor};
+
+// Machine-readable forms of the tree.
+//
+// JSON: one object per node,
+//   {"kind": "BINARY_OP", "value": "+", "span": {...}, "children": [...]}
+// where "value" is only present for nodes that carry a literal, name or operator and
+// "span" holds the TLoc as {"first": {"row", "col", "len"}, "last": {...}}, or null
+// for nodes without a location. MTree::from_json reads the same format back.
+//
+// S-expressions: (KIND [value] children...), without spans, for compact golden output.
+
+impl MTree {
+
+    pub fn to_json(&self) -> String {
+        let mut writer = JsonWriter { out: String::new(), first_child: true };
+        writer.visit_node(self, 0);
+        writer.out.push('\n');
+        writer.out
+    }
+
+    pub fn to_sexpr(&self) -> String {
+        let mut writer = SexprWriter { out: String::new() };
+        writer.visit_node(self, 0);
+        writer.out.push('\n');
+        writer.out
+    }
+
+    // Rebuilds a tree written by `to_json`. Errors are located in the JSON text.
+    pub fn from_json(src: &str) -> Result<Rc<RefCell<MTree>>, Diagnostic> {
+        let mut reader = JsonReader { src: src.chars().collect(), pos: 0, row: 1, col: 1 };
+        let value = reader.value()?;
+        reader.skip_whitespace();
+        if reader.pos < reader.src.len() {
+            return Err(reader.error("unexpected text after the tree"));
+        }
+        node_from_json(&value)
+    }
+}
+
+// objects indented two spaces per level, children listed inside their parent
+struct JsonWriter {
+    out: String,
+    // no sibling has been written yet in the innermost open "children" list
+    first_child: bool,
+}
+
+impl TreeVisitor for JsonWriter {
+    fn visit_node(&mut self, node: &MTree, depth: usize) {
+        let level = 2 * depth;
+        if depth > 0 {
+            self.out.push_str(if self.first_child { "\n" } else { ",\n" });
+            self.out.push_str(&"  ".repeat(level));
+        }
+        let pad = "  ".repeat(level + 1);
+        self.out.push_str("{\n");
+        self.out.push_str(&format!("{}\"kind\": {},\n", pad, json_string(kind_name(&node.token))));
+        if let Some(value) = json_value(&node.token) {
+            self.out.push_str(&format!("{}\"value\": {},\n", pad, value));
+        }
+        self.out.push_str(&format!("{}\"span\": {},\n", pad, json_span(&node.loc)));
+        self.out.push_str(&format!("{}\"children\": [", pad));
+        self.first_child = true;
+        visit::walk_node(self, node, depth);
+        if !node.children.is_empty() {
+            self.out.push('\n');
+            self.out.push_str(&pad);
+        }
+        self.out.push_str("]\n");
+        self.out.push_str(&"  ".repeat(level));
+        self.out.push('}');
+        // whatever is written next is a later sibling of this node
+        self.first_child = false;
+    }
+}
+
+// leaves stay on one line, every child of an inner node starts a new indented line
+struct SexprWriter {
+    out: String,
+}
+
+impl TreeVisitor for SexprWriter {
+    fn visit_node(&mut self, node: &MTree, depth: usize) {
+        if depth > 0 {
+            self.out.push('\n');
+            self.out.push_str(&"  ".repeat(depth));
+        }
+        self.out.push('(');
+        self.out.push_str(kind_name(&node.token));
+        if let Some(value) = sexpr_value(&node.token) {
+            self.out.push(' ');
+            self.out.push_str(&value);
+        }
+        visit::walk_node(self, node, depth);
+        self.out.push(')');
+    }
+}
+
+fn kind_name(token: &TreeCode) -> &'static str {
+    match token {
+        TreeCode::PROGRAM => "PROGRAM",
+        TreeCode::FUNCTION => "FUNCTION",
+        TreeCode::PARAM_LIST => "PARAM_LIST",
+        TreeCode::PARAMETER => "PARAMETER",
+        TreeCode::BLOCK => "BLOCK",
+        TreeCode::EXPRESSION => "EXPRESSION",
+        TreeCode::STATEMENT => "STATEMENT",
+        TreeCode::RETURN => "RETURN",
+        TreeCode::WHILE => "WHILE",
+        TreeCode::IF => "IF",
+        TreeCode::LET => "LET",
+        TreeCode::PRINT => "PRINT",
+        TreeCode::ASSIGN => "ASSIGN",
+        TreeCode::INT_LITERAL(_) => "INT_LITERAL",
+        TreeCode::BOOL_LITERAL(_) => "BOOL_LITERAL",
+        TreeCode::STRING_LITERAL(_) => "STRING_LITERAL",
+        TreeCode::FUNCTION_CALL(_) => "FUNCTION_CALL",
+        TreeCode::BINARY_OP(_) => "BINARY_OP",
+        TreeCode::UNARY_OP(_) => "UNARY_OP",
+        TreeCode::IDENTIFIER(_) => "IDENTIFIER",
+        TreeCode::TYPE(_) => "TYPE",
+        TreeCode::ERROR => "ERROR",
+        TreeCode::EOF => "EOF",
+    }
+}
+
+fn json_value(token: &TreeCode) -> Option<String> {
+    match token {
+        TreeCode::INT_LITERAL(i) => Some(i.to_string()),
+        TreeCode::BOOL_LITERAL(b) => Some(b.to_string()),
+        TreeCode::STRING_LITERAL(s) |
+        TreeCode::FUNCTION_CALL(s) |
+        TreeCode::IDENTIFIER(s) |
+        TreeCode::TYPE(s) => Some(json_string(s)),
+        TreeCode::BINARY_OP(op) => Some(json_string(&op.to_string())),
+        TreeCode::UNARY_OP(op) => Some(json_string(&op.to_string())),
+        _ => None,
+    }
+}
+
+// names and operators are written bare, string literals quoted as in source
+fn sexpr_value(token: &TreeCode) -> Option<String> {
+    match token {
+        TreeCode::STRING_LITERAL(s) => Some(format!("{:?}", s)),
+        TreeCode::INT_LITERAL(i) => Some(i.to_string()),
+        TreeCode::BOOL_LITERAL(b) => Some(b.to_string()),
+        TreeCode::FUNCTION_CALL(s) | TreeCode::IDENTIFIER(s) | TreeCode::TYPE(s) => Some(s.clone()),
+        TreeCode::BINARY_OP(op) => Some(op.to_string()),
+        TreeCode::UNARY_OP(op) => Some(op.to_string()),
+        _ => None,
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_span(loc: &TLoc) -> String {
+    if loc.is_empty() {
+        return "null".to_string();
+    }
+    let pos = |p: &TPos| format!("{{\"row\": {}, \"col\": {}, \"len\": {}}}", p.row, p.col, p.len);
+    format!("{{\"first\": {}, \"last\": {}}}", pos(&loc.first), pos(&loc.last))
+}
+
+// Reading JSON back
+
+enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<JsonValue>),
+    Object(Vec<(String, JsonValue)>),
+}
+
+// a parsed value and where it starts in the JSON text
+struct JsonValue {
+    json: Json,
+    loc: TLoc,
+}
+
+impl JsonValue {
+    fn field(&self, name: &str) -> Option<&JsonValue> {
+        match &self.json {
+            Json::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::error(message, self.loc.clone())
+    }
+}
+
+struct JsonReader {
+    src: Vec<char>,
+    pos: usize,
+    row: usize,
+    col: usize,
+}
+
+impl JsonReader {
+    fn here(&self) -> TLoc {
+        TLoc {
+            first: TPos::new(self.row, self.col, 1),
+            last: TPos::new(self.row, self.col, 1),
+        }
+    }
+
+    fn error(&self, message: &str) -> Diagnostic {
+        Diagnostic::error(message, self.here())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.row += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.bump();
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Diagnostic> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.bump();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn keyword(&mut self, word: &str) -> bool {
+        let end = self.pos + word.chars().count();
+        if end <= self.src.len() && self.src[self.pos..end].iter().copied().eq(word.chars()) {
+            for _ in 0..word.len() {
+                self.bump();
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn value(&mut self) -> Result<JsonValue, Diagnostic> {
+        self.skip_whitespace();
+        let loc = self.here();
+        let json = match self.peek() {
+            Some('{') => self.object()?,
+            Some('[') => self.array()?,
+            Some('"') => Json::Str(self.string()?),
+            Some('-' | '0'..='9') => self.number()?,
+            _ if self.keyword("null") => Json::Null,
+            _ if self.keyword("true") => Json::Bool(true),
+            _ if self.keyword("false") => Json::Bool(false),
+            Some(_) => return Err(self.error("expected a JSON value")),
+            None => return Err(self.error("unexpected end of input")),
+        };
+        Ok(JsonValue { json, loc })
+    }
+
+    fn object(&mut self) -> Result<Json, Diagnostic> {
+        self.expect('{')?;
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected a field name"));
+            }
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.bump() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, Diagnostic> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.bump();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.bump() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Diagnostic> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(out),
+                Some('\\') => match self.bump() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => out.push(c),
+                            None => return Err(self.error(&format!("invalid escape `\\u{}`", hex))),
+                        }
+                    }
+                    _ => return Err(self.error("invalid escape sequence")),
+                },
+                Some(c) => out.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, Diagnostic> {
+        let mut text = String::new();
+        if self.peek() == Some('-') {
+            text.push('-');
+            self.bump();
+        }
+        while let Some(c @ '0'..='9') = self.peek() {
+            text.push(c);
+            self.bump();
+        }
+        if matches!(self.peek(), Some('.' | 'e' | 'E')) {
+            return Err(self.error("only integer numbers appear in a tree"));
+        }
+        text.parse::<i64>()
+            .map(Json::Int)
+            .map_err(|_| self.error(&format!("invalid number `{}`", text)))
+    }
+}
+
+fn node_from_json(value: &JsonValue) -> Result<Rc<RefCell<MTree>>, Diagnostic> {
+    if !matches!(value.json, Json::Object(_)) {
+        return Err(value.error("expected a tree node object"));
+    }
+    let kind = match value.field("kind").map(|k| &k.json) {
+        Some(Json::Str(kind)) => kind.as_str(),
+        _ => return Err(value.error("tree node is missing its \"kind\"")),
+    };
+    let token = token_from_json(kind, value.field("value"), value)?;
+    let loc = match value.field("span") {
+        None => TLoc::empty(),
+        Some(span) => span_from_json(span)?,
+    };
+
+    let node = MTree::with_loc(token, loc);
+    match value.field("children").map(|c| &c.json) {
+        None => {}
+        Some(Json::Array(children)) => {
+            for child in children {
+                node.borrow_mut()._push(node_from_json(child)?);
+            }
+        }
+        Some(_) => return Err(value.error("\"children\" must be an array")),
+    }
+    Ok(node)
+}
+
+fn token_from_json(kind: &str, payload: Option<&JsonValue>, node: &JsonValue) -> Result<TreeCode, Diagnostic> {
+    let missing = || node.error(format!("{} node needs a \"value\" of the right type", kind));
+    let text = || match payload.map(|p| &p.json) {
+        Some(Json::Str(s)) => Ok(s.clone()),
+        _ => Err(missing()),
+    };
+    let token = match kind {
+        "PROGRAM" => TreeCode::PROGRAM,
+        "FUNCTION" => TreeCode::FUNCTION,
+        "PARAM_LIST" => TreeCode::PARAM_LIST,
+        "PARAMETER" => TreeCode::PARAMETER,
+        "BLOCK" => TreeCode::BLOCK,
+        "EXPRESSION" => TreeCode::EXPRESSION,
+        "STATEMENT" => TreeCode::STATEMENT,
+        "RETURN" => TreeCode::RETURN,
+        "WHILE" => TreeCode::WHILE,
+        "IF" => TreeCode::IF,
+        "LET" => TreeCode::LET,
+        "PRINT" => TreeCode::PRINT,
+        "ASSIGN" => TreeCode::ASSIGN,
+        "ERROR" => TreeCode::ERROR,
+        "EOF" => TreeCode::EOF,
+        "INT_LITERAL" => match payload.map(|p| &p.json) {
+            Some(Json::Int(i)) => TreeCode::INT_LITERAL(*i),
+            _ => return Err(missing()),
+        },
+        "BOOL_LITERAL" => match payload.map(|p| &p.json) {
+            Some(Json::Bool(b)) => TreeCode::BOOL_LITERAL(*b),
+            _ => return Err(missing()),
+        },
+        "STRING_LITERAL" => TreeCode::STRING_LITERAL(text()?),
+        "FUNCTION_CALL" => TreeCode::FUNCTION_CALL(text()?),
+        "IDENTIFIER" => TreeCode::IDENTIFIER(text()?),
+        "TYPE" => TreeCode::TYPE(text()?),
+        "BINARY_OP" => {
+            let symbol = text()?;
+            let op = [
+                BinaryOp::ADD, BinaryOp::SUB, BinaryOp::MUL, BinaryOp::DIV, BinaryOp::LT,
+                BinaryOp::GT, BinaryOp::EQUAL, BinaryOp::NOT_EQUAL, BinaryOp::AND, BinaryOp::OR,
+            ].into_iter().find(|op| op.to_string() == symbol);
+            match op {
+                Some(op) => TreeCode::BINARY_OP(op),
+                None => return Err(node.error(format!("unknown binary operator `{}`", symbol))),
+            }
+        }
+        "UNARY_OP" => match text()?.as_str() {
+            "-" => TreeCode::UNARY_OP(UnaryOp::NEG),
+            "!" => TreeCode::UNARY_OP(UnaryOp::NOT),
+            symbol => return Err(node.error(format!("unknown unary operator `{}`", symbol))),
+        },
+        _ => return Err(node.error(format!("unknown node kind `{}`", kind))),
+    };
+    Ok(token)
+}
+
+fn span_from_json(span: &JsonValue) -> Result<TLoc, Diagnostic> {
+    if let Json::Null = span.json {
+        return Ok(TLoc::empty());
+    }
+    let pos = |name: &str| -> Result<TPos, Diagnostic> {
+        let p = span.field(name).ok_or_else(|| span.error(format!("span is missing \"{}\"", name)))?;
+        let num = |field: &str| match p.field(field).map(|v| &v.json) {
+            Some(Json::Int(n)) if *n >= 0 => Ok(*n as usize),
+            _ => Err(p.error(format!("position needs a non-negative \"{}\"", field))),
+        };
+        Ok(TPos::new(num("row")?, num("col")?, num("len")?))
+    };
+    Ok(TLoc { first: pos("first")?, last: pos("last")? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    fn lower(src: &str) -> Rc<RefCell<MTree>> {
+        MTree::lower(&parse_valid(src))
+    }
+
+    fn assert_round_trip(src: &str) {
+        let tree = lower(src);
+        let json = tree.borrow().to_json();
+        let read_back = match MTree::from_json(&json) {
+            Ok(tree) => tree,
+            Err(diag) => panic!("{}", diag.render("<json>", &json)),
+        };
+        assert_eq!(read_back.borrow().to_json(), json);
+        assert_eq!(read_back.borrow().to_sexpr(), tree.borrow().to_sexpr());
+    }
+
+    #[test]
+    fn json_round_trips_the_test_programs() {
+        assert_round_trip(include_str!("Test1"));
+        assert_round_trip(include_str!("Test2"));
+    }
+
+    #[test]
+    fn json_round_trips_every_payload() {
+        assert_round_trip("func f(a: int, b: bool) -> string [
+                               if !b & a > -1 | a == 0 [ return \"tab\\t \\\"quoted\\\" \\\\ done\"; ]
+                               while a != 2 [ a = a * 2 / 1 - 0 + f(a, b < true); ]
+                               return \"\";
+                           ]
+                           func main() [ print f(1, false); ; ]");
+    }
+
+    #[test]
+    fn sexpr_nests_children_under_their_parent() {
+        let expected = "\
+(PROGRAM
+  (FUNCTION
+    (IDENTIFIER main)
+    (PARAM_LIST)
+    (BLOCK
+      (STATEMENT
+        (PRINT
+          (BINARY_OP +
+            (INT_LITERAL 1)
+            (STRING_LITERAL \"a\")))))))
+";
+        assert_eq!(lower("func main() [ print 1 + \"a\"; ]").borrow().to_sexpr(), expected);
+    }
+
+    // the error message and the row and column it points at in the JSON text
+    fn json_error(src: &str) -> (String, usize, usize) {
+        match MTree::from_json(src) {
+            Ok(_) => panic!("expected {:?} to be rejected", src),
+            Err(diag) => (diag.message, diag.loc.first.row, diag.loc.first.col),
+        }
+    }
+
+    #[test]
+    fn node_without_kind_is_rejected_where_it_starts() {
+        let src = "{\"kind\": \"PROGRAM\", \"children\": [\n  {\"span\": null}\n]}";
+        assert_eq!(json_error(src), ("tree node is missing its \"kind\"".to_string(), 2, 3));
+    }
+
+    #[test]
+    fn unknown_operator_is_rejected() {
+        let src = "{\"kind\": \"BINARY_OP\", \"value\": \"%\"}";
+        assert_eq!(json_error(src), ("unknown binary operator `%`".to_string(), 1, 1));
+    }
+
+    #[test]
+    fn negative_row_is_rejected() {
+        let src = "{\"kind\": \"EOF\", \"span\": {\"first\": {\"row\": -1, \"col\": 1, \"len\": 1},\n\"last\": {\"row\": 1, \"col\": 1, \"len\": 1}}}";
+        assert_eq!(json_error(src), ("position needs a non-negative \"row\"".to_string(), 1, 35));
+    }
+
+    #[test]
+    fn malformed_json_is_located() {
+        assert_eq!(json_error("{\"kind\": \"EOF\",\n  \"children\": [}"), ("expected a JSON value".to_string(), 2, 16));
+        assert_eq!(json_error("{\"kind\": \"EOF\"} x"), ("unexpected text after the tree".to_string(), 1, 17));
+        assert_eq!(json_error("{\"kind\": \"INT_LITERAL\", \"value\": 1.5}").0, "only integer numbers appear in a tree");
+    }
+}
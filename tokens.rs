@@ -15,6 +15,7 @@ pub enum TCode {
     ID(String),
     INT(i64),
     BOOL(bool),
+    STRING(String),
 
     // Keywords
     KW_FUNC,
@@ -52,6 +53,10 @@ pub enum TCode {
     // Separators
     SEMICOLON,
     COMMA,
+
+    // Type annotations
+    COLON,
+    ARROW,
 }
 
 impl fmt::Debug for TCode {
@@ -63,6 +68,7 @@ impl fmt::Debug for TCode {
             TCode::ID(name) => write!(f, "ID(\"{}\")", name),
             TCode::INT(value) => write!(f, "INT({})", value),
             TCode::BOOL(value) => write!(f, "BOOL({})", value),
+            TCode::STRING(value) => write!(f, "STRING({:?})", value),
 
             TCode::OP_ASSIGN => write!(f, "="),
             TCode::OP_ADD => write!(f, "+"),
@@ -88,6 +94,8 @@ impl fmt::Debug for TCode {
 
             TCode::COMMA => write!(f, ","),
             TCode::SEMICOLON => write!(f, ";"),
+            TCode::COLON => write!(f, ":"),
+            TCode::ARROW => write!(f, "->"),
 
             TCode::KW_FUNC => write!(f, "FUNC"),
             TCode::KW_LET => write!(f, "LET"),
@@ -126,6 +134,18 @@ impl TLoc {
             last: TPos::new(0, 0, 0),
         }
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.first.row == 0
+    }
+
+    // the span from the start of this location to the end of `end`
+    pub fn to(&self, end: &TLoc) -> TLoc {
+        TLoc {
+            first: self.first.clone(),
+            last: end.last.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -175,9 +195,92 @@ pub enum TreeCode {
 
     INT_LITERAL(i64),
     BOOL_LITERAL(bool),
+    STRING_LITERAL(String),
     FUNCTION_CALL(String),
-    OPERATOR(String),
+    BINARY_OP(BinaryOp),
+    UNARY_OP(UnaryOp),
     IDENTIFIER(String),
+    // annotation attached as the child of the IDENTIFIER it describes
+    TYPE(String),
+
+    // placeholder for a construct the parser could not read; see Parser::synchronize
+    ERROR,
 
     EOF,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    ADD,
+    SUB,
+    MUL,
+    DIV,
+    LT,
+    GT,
+    EQUAL,
+    NOT_EQUAL,
+    AND,
+    OR,
+}
+
+impl BinaryOp {
+    pub fn from_token(code: &TCode) -> Option<BinaryOp> {
+        match code {
+            TCode::OP_ADD => Some(BinaryOp::ADD),
+            TCode::OP_SUB => Some(BinaryOp::SUB),
+            TCode::OP_MUL => Some(BinaryOp::MUL),
+            TCode::OP_DIV => Some(BinaryOp::DIV),
+            TCode::OP_LT => Some(BinaryOp::LT),
+            TCode::OP_GT => Some(BinaryOp::GT),
+            TCode::OP_EQUAL => Some(BinaryOp::EQUAL),
+            TCode::OP_NOT_EQUAL => Some(BinaryOp::NOT_EQUAL),
+            TCode::OP_AND => Some(BinaryOp::AND),
+            TCode::OP_OR => Some(BinaryOp::OR),
+            _ => None,
+        }
+    }
+}
+
+// the operator as written in source
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self {
+            BinaryOp::ADD => "+",
+            BinaryOp::SUB => "-",
+            BinaryOp::MUL => "*",
+            BinaryOp::DIV => "/",
+            BinaryOp::LT => "<",
+            BinaryOp::GT => ">",
+            BinaryOp::EQUAL => "==",
+            BinaryOp::NOT_EQUAL => "!=",
+            BinaryOp::AND => "&",
+            BinaryOp::OR => "|",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    NEG,
+    NOT,
+}
+
+impl UnaryOp {
+    pub fn from_token(code: &TCode) -> Option<UnaryOp> {
+        match code {
+            TCode::OP_SUB => Some(UnaryOp::NEG),
+            TCode::OP_NOT => Some(UnaryOp::NOT),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnaryOp::NEG => write!(f, "-"),
+            UnaryOp::NOT => write!(f, "!"),
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::ast::{Program, Function, TypeName, Block, Stmt, Expr};
+use crate::tokens::{TLoc, BinaryOp, UnaryOp};
+use crate::diagnostics::Diagnostic;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Bool,
+    Str,
+    Void,
+    // not determined statically (e.g. a parameter); compatible with every type
+    Unknown,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Str => write!(f, "string"),
+            Type::Void => write!(f, "void"),
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Type {
+    fn compatible(self, other: Type) -> bool {
+        self == Type::Unknown || other == Type::Unknown || self == other
+    }
+
+    // names usable in annotations; `void` only makes sense as a return type
+    fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "int" => Some(Type::Int),
+            "bool" => Some(Type::Bool),
+            "string" => Some(Type::Str),
+            "void" => Some(Type::Void),
+            _ => None,
+        }
+    }
+}
+
+// declared interface of a function; unannotated parameters are Unknown
+struct FuncType {
+    params: Vec<Type>,
+    ret: Option<(Type, TLoc)>,
+}
+
+// type checking
+// Runs after the analyzer, so names are assumed to resolve; anything that does not
+// is simply treated as Unknown here.
+pub fn check(program: &Program) -> Vec<Diagnostic> {
+    let functions: Vec<&Function> = program.functions().collect();
+
+    // return types depend on each other through calls, so re-infer them until they
+    // settle; only the diagnostics of the last round are kept
+    let mut checker = TypeChecker::new();
+    for _ in 0..=functions.len() {
+        let before = checker.returns.clone();
+        checker.check_functions(&functions);
+        if checker.returns == before {
+            break;
+        }
+    }
+
+    checker.diags
+}
+
+struct TypeChecker {
+    // declared parameter and return types per function name
+    signatures: HashMap<String, FuncType>,
+    // inferred (or declared) return type per function name
+    returns: HashMap<String, Type>,
+    // variable name -> (type, where it was declared)
+    scopes: Vec<HashMap<String, (Type, TLoc)>>,
+    // every `return` seen in the current function body
+    seen_returns: Vec<(Type, TLoc)>,
+    diags: Vec<Diagnostic>,
+}
+
+impl TypeChecker {
+    fn new() -> Self {
+        TypeChecker {
+            signatures: HashMap::new(),
+            returns: HashMap::new(),
+            scopes: vec![],
+            seen_returns: vec![],
+            diags: vec![],
+        }
+    }
+
+    fn check_functions(&mut self, functions: &[&Function]) {
+        self.diags.clear();
+        self.collect_signatures(functions);
+        for func in functions {
+            self.check_function(func);
+        }
+    }
+
+    fn collect_signatures(&mut self, functions: &[&Function]) {
+        self.signatures.clear();
+        for func in functions {
+            // duplicates are reported by the analyzer; the first definition wins
+            if self.signatures.contains_key(&func.name.name) {
+                continue;
+            }
+
+            let params = func.params.iter()
+                .map(|p| self.annotation(p.ty.as_ref(), false).map_or(Type::Unknown, |(ty, _)| ty))
+                .collect();
+            let ret = self.annotation(func.ret.as_ref(), true);
+            self.signatures.insert(func.name.name.clone(), FuncType { params, ret });
+        }
+    }
+
+    // Resolves a written type annotation, if there is one
+    fn annotation(&mut self, ty: Option<&TypeName>, allow_void: bool) -> Option<(Type, TLoc)> {
+        let ty = ty?;
+        match Type::from_name(&ty.name) {
+            Some(Type::Void) if !allow_void => {
+                self.diags.push(Diagnostic::error(
+                    "`void` can only be used as a return type",
+                    ty.loc.clone(),
+                ));
+                None
+            }
+            Some(resolved) => Some((resolved, ty.loc.clone())),
+            None => {
+                self.diags.push(
+                    Diagnostic::error(format!("unknown type `{}`", ty.name), ty.loc.clone())
+                        .with_note("the available types are int, bool and string"),
+                );
+                None
+            }
+        }
+    }
+
+    fn check_function(&mut self, func: &Function) {
+        let name = func.name.name.clone();
+
+        let (param_types, declared_ret) = match self.signatures.get(&name) {
+            Some(sig) => (sig.params.clone(), sig.ret.clone()),
+            None => (vec![], None),
+        };
+
+        self.scopes.push(HashMap::new());
+        for (i, p) in func.params.iter().enumerate() {
+            let ty = param_types.get(i).copied().unwrap_or(Type::Unknown);
+            self.declare(&p.name.name, ty, p.name.loc.clone());
+        }
+
+        self.seen_returns.clear();
+        self.check_block(&func.body);
+        self.scopes.pop();
+
+        // a declared return type is checked against every return
+        if let Some((declared, declared_loc)) = declared_ret {
+            for (ty, loc) in std::mem::take(&mut self.seen_returns) {
+                if !declared.compatible(ty) {
+                    self.diags.push(
+                        Diagnostic::error(
+                            format!("function `{}` is declared to return {} but returns {}", name, declared, ty),
+                            loc,
+                        )
+                        .with_note_at("return type declared here", declared_loc.clone()),
+                    );
+                }
+            }
+            self.returns.insert(name, declared);
+            return;
+        }
+
+        // otherwise the first return with a known type fixes the function's type, the others must agree
+        let mut ret: Option<(Type, TLoc)> = None;
+        for (ty, loc) in std::mem::take(&mut self.seen_returns) {
+            match &ret {
+                Some((first, first_loc)) if *first != Type::Unknown => {
+                    if !first.compatible(ty) {
+                        self.diags.push(
+                            Diagnostic::error(
+                                format!("function `{}` returns {} here but {} elsewhere", name, ty, first),
+                                loc,
+                            )
+                            .with_note_at(format!("first returns {} here", first), first_loc.clone()),
+                        );
+                    }
+                }
+                _ => ret = Some((ty, loc)),
+            }
+        }
+        let ret = match ret {
+            Some((ty, _)) => ty,
+            None => Type::Void,
+        };
+        self.returns.insert(name, ret);
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        self.scopes.push(HashMap::new());
+        for stmt in &block.stmts {
+            self.check_stmt(stmt);
+        }
+        self.scopes.pop();
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(block) => self.check_block(block),
+            Stmt::Let { name, ty, init, .. } => {
+                let init = init.as_ref().map(|expr| (self.check_expr(expr), expr.loc().clone()));
+                let declared = self.annotation(ty.as_ref(), false);
+                let ty = match (declared, init) {
+                    (Some((declared, decl_loc)), Some((init_ty, init_loc))) => {
+                        if !declared.compatible(init_ty) {
+                            self.diags.push(
+                                Diagnostic::error(
+                                    format!("cannot assign {} to `{}`, which has type {}", init_ty, name.name, declared),
+                                    init_loc,
+                                )
+                                .with_note_at("type declared here", decl_loc),
+                            );
+                        }
+                        declared
+                    }
+                    (Some((declared, _)), None) => declared,
+                    (None, Some((init_ty, _))) => init_ty,
+                    (None, None) => Type::Unknown,
+                };
+                self.declare(&name.name, ty, name.loc.clone());
+            }
+            Stmt::Assign { target, value, .. } => {
+                let ty = self.check_expr(value);
+                self.assign(&target.name, ty, &target.loc);
+            }
+            Stmt::Print { value, .. } | Stmt::Expr { expr: value, .. } => {
+                self.check_expr(value);
+            }
+            Stmt::Return { value, loc } => {
+                let ty = self.check_expr(value);
+                self.seen_returns.push((ty, loc.clone()));
+            }
+            Stmt::If { cond, then_block, else_block, .. } => {
+                self.check_condition(cond, "if");
+                self.check_block(then_block);
+                if let Some(else_block) = else_block {
+                    self.check_block(else_block);
+                }
+            }
+            Stmt::While { cond, body, .. } => {
+                self.check_condition(cond, "while");
+                self.check_block(body);
+            }
+            Stmt::Empty { .. } | Stmt::Error { .. } => {}
+        }
+    }
+
+    fn check_condition(&mut self, cond: &Expr, keyword: &str) {
+        let ty = self.check_expr(cond);
+        if !ty.compatible(Type::Bool) {
+            self.diags.push(Diagnostic::error(
+                format!("`{}` condition must be a bool, found {}", keyword, ty),
+                cond.loc().clone(),
+            ));
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Int { .. } => Type::Int,
+            Expr::Bool { .. } => Type::Bool,
+            Expr::Str { .. } => Type::Str,
+            Expr::Var(ident) => self.lookup(&ident.name).map_or(Type::Unknown, |(ty, _)| ty),
+            Expr::Call { name, args, .. } => {
+                let name = &name.name;
+                let params = self.signatures.get(name).map(|sig| sig.params.clone()).unwrap_or_default();
+                for (i, arg) in args.iter().enumerate() {
+                    let ty = self.check_expr(arg);
+                    if let Some(param) = params.get(i) && !param.compatible(ty) {
+                        self.diags.push(Diagnostic::error(
+                            format!("argument {} of `{}` must be {}, found {}", i + 1, name, param, ty),
+                            arg.loc().clone(),
+                        ));
+                    }
+                }
+                self.returns.get(name).copied().unwrap_or(Type::Unknown)
+            }
+            Expr::Unary { op, operand, loc } => {
+                let operand = self.check_expr(operand);
+                self.unary_type(op, operand, loc)
+            }
+            Expr::Binary { op, left, right, loc } => {
+                let left = self.check_expr(left);
+                let right = self.check_expr(right);
+                self.binary_type(op, left, right, loc)
+            }
+        }
+    }
+
+    fn unary_type(&mut self, op: &UnaryOp, operand: Type, loc: &TLoc) -> Type {
+        let expected = match op {
+            UnaryOp::NOT => Type::Bool,
+            UnaryOp::NEG => Type::Int,
+        };
+        if !operand.compatible(expected) {
+            self.diags.push(Diagnostic::error(
+                format!("cannot apply `{}` to {}", op, operand),
+                loc.clone(),
+            ));
+        }
+        expected
+    }
+
+    fn binary_type(&mut self, op: &BinaryOp, left: Type, right: Type, loc: &TLoc) -> Type {
+        use Type::*;
+        let result = match op {
+            // "+" adds ints, or concatenates when either side is a string
+            BinaryOp::ADD => match (left, right) {
+                (Str, Int | Bool | Str | Unknown) | (Int | Bool | Unknown, Str) => Some(Str),
+                (Int, Int) => Some(Int),
+                // an untyped operand could still turn out to be a string
+                (Int | Unknown, Int | Unknown) | (Unknown, Bool) | (Bool, Unknown) => Some(Unknown),
+                _ => None,
+            },
+            BinaryOp::SUB | BinaryOp::MUL | BinaryOp::DIV =>
+                (left.compatible(Int) && right.compatible(Int)).then_some(Int),
+            BinaryOp::LT | BinaryOp::GT => (left.compatible(Int) && right.compatible(Int)).then_some(Bool),
+            BinaryOp::EQUAL | BinaryOp::NOT_EQUAL =>
+                (left.compatible(right) && left != Void && right != Void).then_some(Bool),
+            BinaryOp::AND | BinaryOp::OR => (left.compatible(Bool) && right.compatible(Bool)).then_some(Bool),
+        };
+        match result {
+            Some(ty) => ty,
+            None => {
+                self.diags.push(Diagnostic::error(
+                    format!("cannot apply `{}` to {} and {}", op, left, right),
+                    loc.clone(),
+                ));
+                Unknown
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, ty: Type, loc: TLoc) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), (ty, loc));
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<(Type, TLoc)> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).cloned())
+    }
+
+    // A variable declared without a value takes the type of the first known value assigned
+    fn assign(&mut self, name: &str, ty: Type, loc: &TLoc) {
+        for scope in self.scopes.iter_mut().rev() {
+            if let Some((declared, decl_loc)) = scope.get_mut(name) {
+                if *declared == Type::Unknown {
+                    *declared = ty;
+                } else if !declared.compatible(ty) {
+                    let diag = Diagnostic::error(
+                        format!("cannot assign {} to `{}`, which has type {}", ty, name, declared),
+                        loc.clone(),
+                    )
+                    .with_note_at(format!("`{}` is declared here", name), decl_loc.clone());
+                    self.diags.push(diag);
+                }
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+
+    fn errors(src: &str) -> Vec<String> {
+        check(&parse_valid(src)).into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn declared_return_type_is_enforced() {
+        assert_eq!(
+            errors("func f() -> int [ return true; ] func main() [ print f(); ]"),
+            ["function `f` is declared to return int but returns bool"],
+        );
+    }
+
+    #[test]
+    fn mutual_recursion_settles_on_the_second_round() {
+        // `even` is only known to return bool once both functions have been checked,
+        // so the misuse in `main`, which comes first, is found on the second round
+        let src = "func main() [ print even(4) + 1; ]
+                   func even(n: int) [ if n == 0 [ return true; ] return odd(n - 1); ]
+                   func odd(n: int) [ if n == 0 [ return false; ] return even(n - 1); ]";
+        assert_eq!(errors(src), ["cannot apply `+` to bool and int"]);
+    }
+
+    #[test]
+    fn consistent_mutual_recursion_is_accepted() {
+        let src = "func main() [ let b: bool = even(4); print b; ]
+                   func even(n: int) [ if n == 0 [ return true; ] return odd(n - 1); ]
+                   func odd(n: int) [ if n == 0 [ return false; ] return even(n - 1); ]";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn inferred_return_types_must_agree() {
+        let src = "func main() [ print even(4); ]
+                   func even(n: int) [ if n == 0 [ return true; ] return odd(n - 1); ]
+                   func odd(n: int) [ if n == 0 [ return 1; ] return even(n - 1); ]";
+        assert_eq!(errors(src), [
+            "function `even` returns int here but bool elsewhere",
+            "function `odd` returns bool here but int elsewhere",
+        ]);
+    }
+
+    #[test]
+    fn untyped_operand_may_be_concatenated_with_a_bool() {
+        let src = "func f(s) [ print s + true; print false + s; ] func main() [ f(\"x\"); ]";
+        assert_eq!(errors(src), Vec::<String>::new());
+        assert_eq!(errors("func main() [ print 1 + true; ]"), ["cannot apply `+` to int and bool"]);
+    }
+}
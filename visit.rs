@@ -0,0 +1,326 @@
+use crate::ast::{Program, Item, Function, Param, Ident, TypeName, Block, Stmt, Expr};
+use crate::mtree::MTree;
+
+// Traversal of the typed AST, so a pass only has to handle the nodes it cares about.
+//
+// A Visitor reads the tree: every `visit_*` method defaults to the matching `walk_*`
+// function, which visits the node's children in source order. Override a method to
+// look at a node and call the `walk_*` function from it to keep descending.
+//
+// A Rewriter rebuilds the tree: every `rewrite_*` method takes a node by value and
+// defaults to the matching `fold_*` function, which rewrites the children and puts
+// the node back together.
+//
+// A TreeVisitor walks the generic MTree, where every TreeCode variant is just a node
+// with children; `depth` is 0 at the node the walk started from.
+
+// The whole-program entry points and the Rewriter have no caller among the passes yet;
+// the tests at the bottom of this file exercise them.
+#[cfg_attr(not(test), allow(dead_code))]
+pub trait Visitor<'ast> {
+    fn visit_program(&mut self, program: &'ast Program) { walk_program(self, program) }
+    fn visit_function(&mut self, func: &'ast Function) { walk_function(self, func) }
+    fn visit_param(&mut self, param: &'ast Param) { walk_param(self, param) }
+    fn visit_block(&mut self, block: &'ast Block) { walk_block(self, block) }
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) { walk_stmt(self, stmt) }
+    fn visit_expr(&mut self, expr: &'ast Expr) { walk_expr(self, expr) }
+    // names: functions, parameters, variables, assignment targets and callees
+    fn visit_ident(&mut self, _ident: &'ast Ident) {}
+    fn visit_type(&mut self, _ty: &'ast TypeName) {}
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn walk_program<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, program: &'ast Program) {
+    for item in &program.items {
+        if let Item::Function(func) = item {
+            v.visit_function(func);
+        }
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn walk_function<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, func: &'ast Function) {
+    v.visit_ident(&func.name);
+    for param in &func.params {
+        v.visit_param(param);
+    }
+    if let Some(ret) = &func.ret {
+        v.visit_type(ret);
+    }
+    v.visit_block(&func.body);
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn walk_param<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, param: &'ast Param) {
+    v.visit_ident(&param.name);
+    if let Some(ty) = &param.ty {
+        v.visit_type(ty);
+    }
+}
+
+pub fn walk_block<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, block: &'ast Block) {
+    for stmt in &block.stmts {
+        v.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, stmt: &'ast Stmt) {
+    match stmt {
+        Stmt::Let { name, ty, init, .. } => {
+            v.visit_ident(name);
+            if let Some(ty) = ty {
+                v.visit_type(ty);
+            }
+            if let Some(init) = init {
+                v.visit_expr(init);
+            }
+        }
+        Stmt::Assign { target, value, .. } => {
+            v.visit_ident(target);
+            v.visit_expr(value);
+        }
+        Stmt::If { cond, then_block, else_block, .. } => {
+            v.visit_expr(cond);
+            v.visit_block(then_block);
+            if let Some(else_block) = else_block {
+                v.visit_block(else_block);
+            }
+        }
+        Stmt::While { cond, body, .. } => {
+            v.visit_expr(cond);
+            v.visit_block(body);
+        }
+        Stmt::Return { value, .. } | Stmt::Print { value, .. } | Stmt::Expr { expr: value, .. } => v.visit_expr(value),
+        Stmt::Block(block) => v.visit_block(block),
+        Stmt::Empty { .. } | Stmt::Error { .. } => {}
+    }
+}
+
+pub fn walk_expr<'ast, V: Visitor<'ast> + ?Sized>(v: &mut V, expr: &'ast Expr) {
+    match expr {
+        Expr::Var(ident) => v.visit_ident(ident),
+        Expr::Call { name, args, .. } => {
+            v.visit_ident(name);
+            for arg in args {
+                v.visit_expr(arg);
+            }
+        }
+        Expr::Unary { operand, .. } => v.visit_expr(operand),
+        Expr::Binary { left, right, .. } => {
+            v.visit_expr(left);
+            v.visit_expr(right);
+        }
+        Expr::Int { .. } | Expr::Bool { .. } | Expr::Str { .. } => {}
+    }
+}
+
+pub trait TreeVisitor {
+    fn visit_node(&mut self, node: &MTree, depth: usize) { walk_node(self, node, depth) }
+}
+
+pub fn walk_node<V: TreeVisitor + ?Sized>(v: &mut V, node: &MTree, depth: usize) {
+    for child in &node.children {
+        v.visit_node(&child.borrow(), depth + 1);
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub trait Rewriter {
+    fn rewrite_program(&mut self, program: Program) -> Program { fold_program(self, program) }
+    fn rewrite_function(&mut self, func: Function) -> Function { fold_function(self, func) }
+    fn rewrite_param(&mut self, param: Param) -> Param { fold_param(self, param) }
+    fn rewrite_block(&mut self, block: Block) -> Block { fold_block(self, block) }
+    fn rewrite_stmt(&mut self, stmt: Stmt) -> Stmt { fold_stmt(self, stmt) }
+    fn rewrite_expr(&mut self, expr: Expr) -> Expr { fold_expr(self, expr) }
+    fn rewrite_ident(&mut self, ident: Ident) -> Ident { ident }
+    fn rewrite_type(&mut self, ty: TypeName) -> TypeName { ty }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_program<R: Rewriter + ?Sized>(r: &mut R, program: Program) -> Program {
+    let items = program.items.into_iter()
+        .map(|item| match item {
+            Item::Function(func) => Item::Function(Box::new(r.rewrite_function(*func))),
+            Item::Error { loc } => Item::Error { loc },
+        })
+        .collect();
+    Program { items }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_function<R: Rewriter + ?Sized>(r: &mut R, func: Function) -> Function {
+    Function {
+        name: r.rewrite_ident(func.name),
+        params: func.params.into_iter().map(|p| r.rewrite_param(p)).collect(),
+        params_loc: func.params_loc,
+        ret: func.ret.map(|ty| r.rewrite_type(ty)),
+        body: r.rewrite_block(func.body),
+        loc: func.loc,
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_param<R: Rewriter + ?Sized>(r: &mut R, param: Param) -> Param {
+    Param {
+        name: r.rewrite_ident(param.name),
+        ty: param.ty.map(|ty| r.rewrite_type(ty)),
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_block<R: Rewriter + ?Sized>(r: &mut R, block: Block) -> Block {
+    Block {
+        stmts: block.stmts.into_iter().map(|s| r.rewrite_stmt(s)).collect(),
+        loc: block.loc,
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_stmt<R: Rewriter + ?Sized>(r: &mut R, stmt: Stmt) -> Stmt {
+    match stmt {
+        Stmt::Let { name, ty, init, loc } => Stmt::Let {
+            name: r.rewrite_ident(name),
+            ty: ty.map(|ty| r.rewrite_type(ty)),
+            init: init.map(|e| r.rewrite_expr(e)),
+            loc,
+        },
+        Stmt::Assign { target, value, loc } => Stmt::Assign {
+            target: r.rewrite_ident(target),
+            value: r.rewrite_expr(value),
+            loc,
+        },
+        Stmt::If { cond, then_block, else_block, loc } => Stmt::If {
+            cond: r.rewrite_expr(cond),
+            then_block: r.rewrite_block(then_block),
+            else_block: else_block.map(|b| r.rewrite_block(b)),
+            loc,
+        },
+        Stmt::While { cond, body, loc } => Stmt::While {
+            cond: r.rewrite_expr(cond),
+            body: r.rewrite_block(body),
+            loc,
+        },
+        Stmt::Return { value, loc } => Stmt::Return { value: r.rewrite_expr(value), loc },
+        Stmt::Print { value, loc } => Stmt::Print { value: r.rewrite_expr(value), loc },
+        Stmt::Block(block) => Stmt::Block(r.rewrite_block(block)),
+        Stmt::Expr { expr, loc } => Stmt::Expr { expr: r.rewrite_expr(expr), loc },
+        Stmt::Empty { loc } => Stmt::Empty { loc },
+        Stmt::Error { loc } => Stmt::Error { loc },
+    }
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn fold_expr<R: Rewriter + ?Sized>(r: &mut R, expr: Expr) -> Expr {
+    match expr {
+        Expr::Var(ident) => Expr::Var(r.rewrite_ident(ident)),
+        Expr::Call { name, args, loc } => Expr::Call {
+            name: r.rewrite_ident(name),
+            args: args.into_iter().map(|a| r.rewrite_expr(a)).collect(),
+            loc,
+        },
+        Expr::Unary { op, operand, loc } => Expr::Unary {
+            op,
+            operand: Box::new(r.rewrite_expr(*operand)),
+            loc,
+        },
+        Expr::Binary { op, left, right, loc } => Expr::Binary {
+            op,
+            left: Box::new(r.rewrite_expr(*left)),
+            right: Box::new(r.rewrite_expr(*right)),
+            loc,
+        },
+        literal @ (Expr::Int { .. } | Expr::Bool { .. } | Expr::Str { .. }) => literal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_valid;
+    use crate::tokens::BinaryOp;
+
+    fn sexpr(program: &Program) -> String {
+        MTree::lower(program).borrow().to_sexpr()
+    }
+
+    #[derive(Default)]
+    struct Names {
+        idents: Vec<String>,
+        types: Vec<String>,
+    }
+
+    impl Visitor<'_> for Names {
+        fn visit_ident(&mut self, ident: &Ident) {
+            self.idents.push(ident.name.clone());
+        }
+        fn visit_type(&mut self, ty: &TypeName) {
+            self.types.push(ty.name.clone());
+        }
+    }
+
+    #[test]
+    fn visitor_reaches_every_name_in_source_order() {
+        let program = parse_valid("func add(a: int, b) -> int [ let c = a + b; return add(c, 1); ]
+                             func main() [ print add(1, 2); ]");
+        let mut names = Names::default();
+        names.visit_program(&program);
+        assert_eq!(names.idents, ["add", "a", "b", "c", "a", "b", "add", "c", "main", "add"]);
+        assert_eq!(names.types, ["int", "int"]);
+    }
+
+    struct Identity;
+
+    impl Rewriter for Identity {}
+
+    #[test]
+    fn default_fold_rebuilds_the_tree_unchanged() {
+        let program = parse_valid("func f(n: int) -> int [ if n < 1 [ return 0; ] else [ n = -n; ] while n > 0 [ n = n - 1; ] return f(n); ]
+                             func main() [ let s: string = \"x\"; print f(3); [ ; ] ]");
+        let before = MTree::lower(&program).borrow().to_json();
+        let after = MTree::lower(&Identity.rewrite_program(program)).borrow().to_json();
+        assert_eq!(before, after);
+    }
+
+    // folds `+` and `*` of two integer literals, innermost first
+    struct FoldConstants;
+
+    impl Rewriter for FoldConstants {
+        fn rewrite_expr(&mut self, expr: Expr) -> Expr {
+            match fold_expr(self, expr) {
+                Expr::Binary { op, left, right, loc } => match (op, *left, *right) {
+                    (BinaryOp::ADD, Expr::Int { value: a, .. }, Expr::Int { value: b, .. }) => Expr::Int { value: a + b, loc },
+                    (BinaryOp::MUL, Expr::Int { value: a, .. }, Expr::Int { value: b, .. }) => Expr::Int { value: a * b, loc },
+                    (op, left, right) => Expr::Binary { op, left: Box::new(left), right: Box::new(right), loc },
+                },
+                expr => expr,
+            }
+        }
+    }
+
+    #[test]
+    fn overridden_rewrite_expr_changes_the_tree() {
+        let program = parse_valid("func main() [ let x = 1 + 2 * 3; print x + 4; ]");
+        let folded = FoldConstants.rewrite_program(program);
+        assert_eq!(sexpr(&folded), sexpr(&parse_valid("func main() [ let x = 7; print x + 4; ]")));
+    }
+
+    #[test]
+    fn tree_visitor_walks_children_depth_first() {
+        struct Depths(Vec<(String, usize)>);
+        impl TreeVisitor for Depths {
+            fn visit_node(&mut self, node: &MTree, depth: usize) {
+                self.0.push((node.node_string(), depth));
+                walk_node(self, node, depth);
+            }
+        }
+        let tree = MTree::lower(&parse_valid("func main() [ print -1; ]"));
+        let mut depths = Depths(vec![]);
+        depths.visit_node(&tree.borrow(), 0);
+        let expected = [
+            ("PROGRAM", 0), ("FUNCTION", 1), ("IDENTIFIER(\"main\")", 2), ("PARAM_LIST", 2),
+            ("BLOCK", 2), ("STATEMENT", 3), ("PRINT", 4), ("UNARY_OP(-)", 5), ("INT_LITERAL(1)", 6),
+        ];
+        let expected: Vec<(String, usize)> = expected.iter().map(|(s, d)| (s.to_string(), *d)).collect();
+        assert_eq!(depths.0, expected);
+    }
+}